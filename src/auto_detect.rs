@@ -0,0 +1,224 @@
+use std::path::Path;
+
+use k8s_openapi::api::core::v1::Node;
+use kube::{Api, Client};
+use tracing::{debug, info, warn};
+
+use crate::error::Error;
+use crate::host_root::HostRoot;
+use crate::{CloudProvider, load_kube_config};
+
+const DMI_ID_PATH: &str = "/sys/class/dmi/id";
+
+// Azure uses the same DMI vendor as any other Hyper-V VM,
+// but always sets this chassis asset tag.
+const AZURE_CHASSIS_ASSET_TAG: &str = "7783-7084-3265-9085-8269-3286-77";
+
+pub(crate) async fn detect_cloud_provider(
+    node_name: Option<&str>,
+    host_root: &HostRoot,
+) -> Result<CloudProvider, Error> {
+    let from_dmi = detect_from_dmi(&host_root.path(DMI_ID_PATH));
+    let from_provider_id = match node_name {
+        Some(node_name) => node_provider_id(node_name)
+            .await
+            .and_then(|provider_id| detect_from_provider_id(&provider_id)),
+        None => {
+            debug!("No node name given, skipping provider ID detection");
+            None
+        }
+    };
+    resolve(from_dmi, from_provider_id)
+}
+
+fn resolve(
+    from_dmi: Option<CloudProvider>,
+    from_provider_id: Option<CloudProvider>,
+) -> Result<CloudProvider, Error> {
+    match (from_dmi, from_provider_id) {
+        (Some(dmi), Some(provider_id)) if dmi != provider_id => Err(Error::Unsupported(format!(
+            "Cloud provider detection is ambiguous: DMI data says {dmi:?}, but the node's provider ID says {provider_id:?}. Pass --cloud-provider explicitly."
+        ))),
+        (Some(dmi), _) => {
+            info!("Detected cloud provider {dmi:?} from DMI data");
+            Ok(dmi)
+        }
+        (None, Some(provider_id)) => {
+            info!("Detected cloud provider {provider_id:?} from the node's provider ID");
+            Ok(provider_id)
+        }
+        (None, None) => Err(Error::Unsupported(
+            "Unable to detect cloud provider from DMI data or the node's provider ID. Pass --cloud-provider explicitly.".to_owned(),
+        )),
+    }
+}
+
+fn detect_from_dmi(dmi_path: &Path) -> Option<CloudProvider> {
+    let read = |name: &str| {
+        std::fs::read_to_string(dmi_path.join(name))
+            .map(|value| value.trim().to_owned())
+            .unwrap_or_default()
+    };
+    let sys_vendor = read("sys_vendor");
+    let product_name = read("product_name");
+    let chassis_asset_tag = read("chassis_asset_tag");
+    debug!(
+        "DMI data: sys_vendor='{sys_vendor}' product_name='{product_name}' chassis_asset_tag='{chassis_asset_tag}'"
+    );
+
+    if sys_vendor == "Amazon EC2" || chassis_asset_tag == "Amazon EC2" {
+        Some(CloudProvider::Aws)
+    } else if sys_vendor == "Google" || product_name == "Google Compute Engine" {
+        Some(CloudProvider::Gcp)
+    } else if sys_vendor == "Microsoft Corporation" && chassis_asset_tag == AZURE_CHASSIS_ASSET_TAG
+    {
+        Some(CloudProvider::Azure)
+    } else if chassis_asset_tag == "OracleCloud.com" {
+        Some(CloudProvider::Oci)
+    } else if sys_vendor == "Alibaba Cloud" {
+        Some(CloudProvider::Alibaba)
+    } else {
+        None
+    }
+}
+
+fn detect_from_provider_id(provider_id: &str) -> Option<CloudProvider> {
+    // OKE sets the provider ID to the bare instance OCID.
+    if provider_id.starts_with("ocid1.instance.") {
+        return Some(CloudProvider::Oci);
+    }
+    let (scheme, _) = provider_id.split_once("://")?;
+    match scheme {
+        "aws" => Some(CloudProvider::Aws),
+        "gce" => Some(CloudProvider::Gcp),
+        "azure" => Some(CloudProvider::Azure),
+        _ => {
+            debug!("Unrecognized provider ID '{provider_id}'");
+            None
+        }
+    }
+}
+
+async fn node_provider_id(node_name: &str) -> Option<String> {
+    let client = match load_kube_config()
+        .await
+        .and_then(|config| Client::try_from(config).map_err(Error::kube("failed to create client")))
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Failed to connect to Kubernetes to read the node's provider ID: {e}");
+            return None;
+        }
+    };
+    let node_api: Api<Node> = Api::all(client);
+    match node_api.get(node_name).await {
+        Ok(node) => node.spec.and_then(|spec| spec.provider_id),
+        Err(e) => {
+            warn!("Failed to get node {node_name} to read its provider ID: {e:?}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use crate::CloudProvider;
+    use crate::auto_detect::{detect_from_dmi, detect_from_provider_id, resolve};
+    use crate::error::Error;
+
+    fn fake_dmi(sys_vendor: &str, product_name: &str, chassis_asset_tag: &str) -> TempDir {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("sys_vendor"), format!("{sys_vendor}\n")).unwrap();
+        fs::write(dir.path().join("product_name"), format!("{product_name}\n")).unwrap();
+        fs::write(
+            dir.path().join("chassis_asset_tag"),
+            format!("{chassis_asset_tag}\n"),
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_detect_from_dmi() {
+        let dmi = fake_dmi("Amazon EC2", "i4i.xlarge", "Amazon EC2");
+        assert_eq!(detect_from_dmi(dmi.path()), Some(CloudProvider::Aws));
+
+        let dmi = fake_dmi("Google", "Google Compute Engine", "");
+        assert_eq!(detect_from_dmi(dmi.path()), Some(CloudProvider::Gcp));
+
+        let dmi = fake_dmi(
+            "Microsoft Corporation",
+            "Virtual Machine",
+            "7783-7084-3265-9085-8269-3286-77",
+        );
+        assert_eq!(detect_from_dmi(dmi.path()), Some(CloudProvider::Azure));
+
+        let dmi = fake_dmi(
+            "QEMU",
+            "Standard PC (i440FX + PIIX, 1996)",
+            "OracleCloud.com",
+        );
+        assert_eq!(detect_from_dmi(dmi.path()), Some(CloudProvider::Oci));
+
+        let dmi = fake_dmi("Alibaba Cloud", "Alibaba Cloud ECS", "");
+        assert_eq!(detect_from_dmi(dmi.path()), Some(CloudProvider::Alibaba));
+
+        // Plain Hyper-V, not Azure.
+        let dmi = fake_dmi("Microsoft Corporation", "Virtual Machine", "");
+        assert_eq!(detect_from_dmi(dmi.path()), None);
+
+        let empty = TempDir::new().unwrap();
+        assert_eq!(detect_from_dmi(empty.path()), None);
+    }
+
+    #[test]
+    fn test_detect_from_provider_id() {
+        assert_eq!(
+            detect_from_provider_id("aws:///us-east-1a/i-0123456789abcdef0"),
+            Some(CloudProvider::Aws)
+        );
+        assert_eq!(
+            detect_from_provider_id("gce://my-project/us-central1-a/my-node"),
+            Some(CloudProvider::Gcp)
+        );
+        assert_eq!(
+            detect_from_provider_id(
+                "azure:///subscriptions/sub/resourceGroups/rg/providers/Microsoft.Compute/virtualMachineScaleSets/vmss/virtualMachines/0"
+            ),
+            Some(CloudProvider::Azure)
+        );
+        assert_eq!(
+            detect_from_provider_id("ocid1.instance.oc1.iad.anuwcljrexample"),
+            Some(CloudProvider::Oci)
+        );
+        assert_eq!(detect_from_provider_id("kind://docker/kind/node"), None);
+        assert_eq!(detect_from_provider_id(""), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve(Some(CloudProvider::Gcp), Some(CloudProvider::Gcp)).unwrap(),
+            CloudProvider::Gcp
+        );
+        assert_eq!(
+            resolve(Some(CloudProvider::Aws), None).unwrap(),
+            CloudProvider::Aws
+        );
+        assert_eq!(
+            resolve(None, Some(CloudProvider::Azure)).unwrap(),
+            CloudProvider::Azure
+        );
+        assert!(matches!(resolve(None, None), Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_resolve_disagreement() {
+        let err = resolve(Some(CloudProvider::Aws), Some(CloudProvider::Gcp)).unwrap_err();
+        assert!(err.to_string().contains("ambiguous"), "{err}");
+    }
+}
@@ -0,0 +1,318 @@
+//! Which local disks each instance type should come with,
+//! so we can tell when detection finds something else.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+
+use crate::CloudProvider;
+use crate::selector::parse_size;
+
+const EMBEDDED_CATALOG: &str = include_str!("catalog.yaml");
+
+// Providers round disk sizes in their docs,
+// and some of the disk is reserved, so we allow some slack.
+const SIZE_TOLERANCE_PERCENT: u64 = 5;
+
+#[derive(Debug, PartialEq)]
+pub struct CatalogError(String);
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid disk catalog: {}", self.0)
+    }
+}
+
+impl std::error::Error for CatalogError {}
+
+/// Expected local disks, keyed by cloud provider and instance type.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Catalog {
+    entries: HashMap<CloudProvider, HashMap<String, CatalogEntry>>,
+}
+
+/// The local disks an instance type comes with.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CatalogEntry {
+    pub disks: DiskCount,
+    /// Size of each disk in bytes.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub size: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum DiskCount {
+    Exactly(usize),
+    /// The number is picked when creating the instance.
+    OneOf(Vec<usize>),
+}
+
+/// A difference between the catalog and the devices we detected.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CatalogMismatch {
+    DiskCount {
+        instance_type: String,
+        expected: DiskCount,
+        found: usize,
+    },
+    DiskSize {
+        instance_type: String,
+        device: String,
+        expected: u64,
+        found: Option<u64>,
+    },
+}
+
+/// What to do when the detected devices don't match the catalog.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CatalogPolicy {
+    /// Don't compare with the catalog.
+    Ignore,
+    /// Log a warning for each mismatch, and carry on.
+    #[default]
+    Warn,
+    /// Fail without configuring any devices.
+    Fail,
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let size = String::deserialize(deserializer)?;
+    parse_size(&size).map_err(serde::de::Error::custom)
+}
+
+impl fmt::Display for DiskCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskCount::Exactly(count) => write!(f, "{count}"),
+            DiskCount::OneOf(counts) => write!(f, "one of {counts:?}"),
+        }
+    }
+}
+
+impl DiskCount {
+    fn allows(&self, count: usize) -> bool {
+        match self {
+            DiskCount::Exactly(expected) => *expected == count,
+            DiskCount::OneOf(expected) => expected.contains(&count),
+        }
+    }
+
+    /// The number of disks, if there is only one possibility.
+    pub fn exact(&self) -> Option<usize> {
+        match self {
+            DiskCount::Exactly(count) => Some(*count),
+            DiskCount::OneOf(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for CatalogMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CatalogMismatch::DiskCount {
+                instance_type,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{instance_type} should have {expected} local disks, but found {found}"
+            ),
+            CatalogMismatch::DiskSize {
+                instance_type,
+                device,
+                expected,
+                found: Some(found),
+            } => write!(
+                f,
+                "{instance_type} local disks should be {expected} bytes, but '{device}' is {found} bytes"
+            ),
+            CatalogMismatch::DiskSize {
+                instance_type,
+                device,
+                expected,
+                found: None,
+            } => write!(
+                f,
+                "{instance_type} local disks should be {expected} bytes, but the size of '{device}' is unknown"
+            ),
+        }
+    }
+}
+
+impl Default for Catalog {
+    fn default() -> Self {
+        Catalog::embedded()
+    }
+}
+
+impl Catalog {
+    /// The catalog compiled into the binary.
+    pub fn embedded() -> Catalog {
+        Catalog::parse(EMBEDDED_CATALOG).expect("embedded disk catalog is valid")
+    }
+
+    pub fn parse(yaml: &str) -> Result<Catalog, CatalogError> {
+        let raw: HashMap<String, HashMap<String, CatalogEntry>> =
+            serde_yaml::from_str(yaml).map_err(|e| CatalogError(e.to_string()))?;
+        let mut entries = HashMap::new();
+        for (provider, instance_types) in raw {
+            let cloud_provider = CloudProvider::from_str(&provider, true)
+                .map_err(|_| CatalogError(format!("unknown cloud provider '{provider}'")))?;
+            entries.insert(cloud_provider, instance_types);
+        }
+        Ok(Catalog { entries })
+    }
+
+    /// Adds the entries in the file at `path`,
+    /// replacing any we already have for the same instance types.
+    pub fn with_overrides(mut self, path: &Path) -> Result<Catalog, CatalogError> {
+        let yaml = std::fs::read_to_string(path)
+            .map_err(|e| CatalogError(format!("failed to read '{}': {e}", path.display())))?;
+        let overrides = Catalog::parse(&yaml)?;
+        for (cloud_provider, instance_types) in overrides.entries {
+            self.entries
+                .entry(cloud_provider)
+                .or_default()
+                .extend(instance_types);
+        }
+        Ok(self)
+    }
+
+    pub fn get(&self, cloud_provider: CloudProvider, instance_type: &str) -> Option<&CatalogEntry> {
+        self.entries.get(&cloud_provider)?.get(instance_type)
+    }
+}
+
+impl CatalogEntry {
+    /// Whether a disk of `size` bytes could be one of these.
+    pub fn size_matches(&self, size: u64) -> bool {
+        size.abs_diff(self.size) <= self.size / 100 * SIZE_TOLERANCE_PERCENT
+    }
+
+    /// Compares detected devices, as paths and sizes, with this entry.
+    pub fn compare(
+        &self,
+        instance_type: &str,
+        devices: &[(String, Option<u64>)],
+    ) -> Vec<CatalogMismatch> {
+        let mut mismatches = vec![];
+        if !self.disks.allows(devices.len()) {
+            mismatches.push(CatalogMismatch::DiskCount {
+                instance_type: instance_type.to_owned(),
+                expected: self.disks.clone(),
+                found: devices.len(),
+            });
+        }
+        for (device, size) in devices {
+            if !size.is_some_and(|size| self.size_matches(size)) {
+                mismatches.push(CatalogMismatch::DiskSize {
+                    instance_type: instance_type.to_owned(),
+                    device: device.clone(),
+                    expected: self.size,
+                    found: *size,
+                });
+            }
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::CloudProvider;
+    use crate::catalog::{Catalog, CatalogEntry, CatalogError, CatalogMismatch, DiskCount};
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_embedded_catalog() {
+        let catalog = Catalog::embedded();
+        assert_eq!(
+            catalog.get(CloudProvider::Aws, "i4i.8xlarge"),
+            Some(&CatalogEntry {
+                disks: DiskCount::Exactly(2),
+                size: 3_750_000_000_000,
+            })
+        );
+        assert_eq!(
+            catalog.get(CloudProvider::Gcp, "n2-standard-16"),
+            Some(&CatalogEntry {
+                disks: DiskCount::OneOf(vec![2, 4, 8, 16, 24]),
+                size: 375 << 30,
+            })
+        );
+        assert_eq!(catalog.get(CloudProvider::Gcp, "i4i.8xlarge"), None);
+        assert_eq!(catalog.get(CloudProvider::Aws, "t3.micro"), None);
+    }
+
+    #[test]
+    fn test_catalog_overrides() {
+        let test_env = TestEnv::new();
+        let path = test_env.temp_dir.path().join("catalog.yaml");
+        std::fs::write(
+            &path,
+            "aws:\n  i4i.8xlarge: {disks: 3, size: 1 TB}\n  x9.huge: {disks: 1, size: 2 TB}\n",
+        )
+        .unwrap();
+        let catalog = Catalog::embedded().with_overrides(&path).unwrap();
+        assert_eq!(
+            catalog
+                .get(CloudProvider::Aws, "i4i.8xlarge")
+                .unwrap()
+                .disks,
+            DiskCount::Exactly(3)
+        );
+        assert!(catalog.get(CloudProvider::Aws, "x9.huge").is_some());
+        assert!(catalog.get(CloudProvider::Aws, "i4i.large").is_some());
+
+        assert_eq!(
+            Catalog::parse("nimbus:\n  big: {disks: 1, size: 1 TB}\n"),
+            Err(CatalogError("unknown cloud provider 'nimbus'".to_owned()))
+        );
+        assert!(Catalog::parse("aws:\n  big: {disks: 1, size: huge}\n").is_err());
+    }
+
+    #[test]
+    fn test_catalog_compare() {
+        let catalog = Catalog::embedded();
+        let entry = catalog.get(CloudProvider::Aws, "r6gd.2xlarge").unwrap();
+        // lsblk reports 441.4G for this one.
+        let devices = vec![("/dev/nvme1n1".to_owned(), Some(473949641113))];
+        assert_eq!(entry.compare("r6gd.2xlarge", &devices), vec![]);
+
+        let entry = catalog.get(CloudProvider::Aws, "i4i.8xlarge").unwrap();
+        assert_eq!(
+            entry.compare("i4i.8xlarge", &devices),
+            vec![
+                CatalogMismatch::DiskCount {
+                    instance_type: "i4i.8xlarge".to_owned(),
+                    expected: DiskCount::Exactly(2),
+                    found: 1,
+                },
+                CatalogMismatch::DiskSize {
+                    instance_type: "i4i.8xlarge".to_owned(),
+                    device: "/dev/nvme1n1".to_owned(),
+                    expected: 3_750_000_000_000,
+                    found: Some(473949641113),
+                },
+            ]
+        );
+
+        let entry = catalog.get(CloudProvider::Gcp, "n2-standard-16").unwrap();
+        let devices: Vec<(String, Option<u64>)> = (0..4)
+            .map(|i| (format!("/dev/nvme{i}n1"), Some(375 << 30)))
+            .collect();
+        assert_eq!(entry.compare("n2-standard-16", &devices), vec![]);
+        assert_eq!(
+            entry.compare("n2-standard-16", &devices[..3]),
+            vec![CatalogMismatch::DiskCount {
+                instance_type: "n2-standard-16".to_owned(),
+                expected: DiskCount::OneOf(vec![2, 4, 8, 16, 24]),
+                found: 3,
+            }]
+        );
+    }
+}
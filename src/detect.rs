@@ -1,31 +1,226 @@
-use serde::Deserialize;
-use tracing::{debug, info, trace};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
 
+use clap::ValueEnum;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::{debug, info, trace, warn};
+
+use crate::catalog::{Catalog, CatalogEntry, CatalogPolicy};
+use crate::detect::host_disks::HostDisks;
+use crate::disk_ids::DiskIds;
+use crate::error::Error;
+use crate::host_root::HostRoot;
+use crate::in_use::check_not_in_use;
+use crate::metadata::InstanceMetadata;
+use crate::nvme::{
+    IdentifyController, IoctlNvmeAdmin, NvmeAdmin, SmartLog, controller_path, split_namespace,
+};
+use crate::selector::{Field, FieldValue, Selectable, Selector, parse_size};
 use crate::{CloudProvider, Commander};
 
-const BOTTLEROCKET_ROOTFS_PATH: &str = "/.bottlerocket/rootfs";
+mod host_disks;
+pub(crate) mod sysfs;
+
+// Filesystem label cloud-init and Nova give the first ephemeral disk.
+const OPENSTACK_EPHEMERAL_LABEL: &str = "ephemeral0";
+// Filesystem label of the OpenStack config drive.
+const OPENSTACK_CONFIG_DRIVE_LABEL: &str = "config-2";
+
+// Where udev makes the google-local-* and google-persistent-disk-* links.
+const GCP_DISK_LINKS_DIR: &str = "/dev/disk/by-id";
+// Models GCP gives local SSDs over NVMe and SCSI.
+// Persistent disks are `nvme_card-pd` and `PersistentDisk`.
+const GCP_LOCAL_SSD_MODELS: &[&str] = &["nvme_card", "EphemeralDisk"];
+// Size of each local SSD, on machine types that aren't in the catalog.
+const GCP_LOCAL_SSD_SIZE: u64 = 375 << 30;
+
+// Models of the local NVMe disks on OCI DenseIO shapes: Samsung PM1725a
+// on DenseIO2, PM1733 on DenseIO.E4, and PM1743 on DenseIO.E5.
+const OCI_DENSE_IO_MODEL_PREFIXES: &[&str] = &["SAMSUNG MZWLL", "SAMSUNG MZWLJ", "SAMSUNG MZWLO"];
+
+// Model of the local NVMe disks on Alibaba Cloud i-series instances.
+const ALIBABA_LOCAL_DISK_MODELS: &[&str] = &["ALIBABA CLOUD LOCAL NVME SSD"];
+
+// udev link to the Azure temporary resource disk, from the Azure storage rules.
+const AZURE_RESOURCE_DISK_LINK: &str = "/dev/disk/azure/resource";
+// Model Hyper-V gives every SCSI disk, including the OS and data disks.
+const AZURE_VIRTUAL_DISK_MODEL: &str = "Virtual Disk";
+// Filesystem label of the resource disk before waagent formats it.
+const AZURE_RESOURCE_DISK_LABEL: &str = "Temporary Storage";
+// Azure Linux agent config, on the host.
+const AZURE_WAAGENT_CONF_PATH: &str = "/etc/waagent.conf";
+
+// How often we look for devices again while waiting for them to appear.
+const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Matches devices lsblk reports without a transport in a transport policy.
+const NO_TRANSPORT: &str = "none";
 
 #[derive(Deserialize)]
 struct Lsblk {
     blockdevices: Vec<LsblkBlockDevice>,
 }
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(try_from = "RawLsblkBlockDevice")]
 struct LsblkBlockDevice {
+    #[serde(skip_serializing)]
     children: Option<Vec<LsblkBlockDevice>>,
+    // Filesystem label.
+    label: Option<String>,
     // Arbitrary string identifying the device model.
     // Not all cloud providers set this to a reasonable value.
     // GCP :(
     model: Option<String>,
-    mountpoint: Option<String>,
+    // Everywhere the device is mounted, if anywhere.
+    mountpoints: Vec<String>,
+    // Device serial number. Some cloud providers encode the volume ID here.
+    serial: Option<String>,
     // Device path (ie: /dev/nvme0n1)
-    // Note that in bottlerocket, this still only starts with /dev,
-    // even though we're in a container that has it in /.bottlerocket/rootfs/dev
+    // Note that this is the path on the host, which may not be where we
+    // can see it, like in Bottlerocket bootstrap containers.
+    // It is resolved through the host root at the end of detection.
     path: String,
     // Connection of device (nvme, sata, etc...)
     tran: Option<String>,
     // Type of device (disk, part, etc...)
     #[serde(rename = "type")]
     type_: String,
+    // Vendor string, padded with trailing spaces.
+    // Usually only set for SCSI devices.
+    vendor: Option<String>,
+    // World wide name of the device.
+    wwn: Option<String>,
+    // Size in bytes.
+    size: Option<u64>,
+    // Whether the device is rotational.
+    rota: Option<bool>,
+}
+
+/// A block device as some version of lsblk reports it.
+/// Columns come and go between util-linux versions, so we look at which
+/// ones are there for each device, and build a `LsblkBlockDevice` from them.
+#[derive(Deserialize)]
+struct RawLsblkBlockDevice {
+    children: Option<Vec<LsblkBlockDevice>>,
+    // The path, with --paths.
+    name: Option<String>,
+    kname: Option<String>,
+    // Added in util-linux 2.33.
+    path: Option<String>,
+    label: Option<String>,
+    model: Option<String>,
+    // Only one of the mountpoints, even when there are more.
+    mountpoint: Option<String>,
+    // Added in util-linux 2.37, with a null entry when not mounted.
+    mountpoints: Option<Vec<Option<String>>>,
+    serial: Option<String>,
+    // Some versions report null for partitions.
+    tran: Option<String>,
+    #[serde(rename = "type")]
+    type_: String,
+    vendor: Option<String>,
+    wwn: Option<String>,
+    // We ask lsblk for bytes, but older versions still report it as a string,
+    // and some of our test data was captured with human readable sizes.
+    #[serde(default, deserialize_with = "deserialize_size")]
+    size: Option<u64>,
+    // Older versions of lsblk report this as "0" or "1".
+    #[serde(default, deserialize_with = "deserialize_bool")]
+    rota: Option<bool>,
+}
+
+impl TryFrom<RawLsblkBlockDevice> for LsblkBlockDevice {
+    type Error = String;
+
+    fn try_from(raw: RawLsblkBlockDevice) -> Result<Self, Self::Error> {
+        let path = match (raw.path, raw.name, raw.kname) {
+            (Some(path), _, _) => path,
+            (None, Some(name), _) if name.starts_with('/') => name,
+            // The kernel's name is always in /dev, even for device mapper devices.
+            (None, _, Some(kname)) => format!("/dev/{kname}"),
+            (None, Some(name), None) => format!("/dev/{name}"),
+            (None, None, None) => return Err("block device has no path, name or kname".to_owned()),
+        };
+        let mut mountpoints: Vec<String> =
+            raw.mountpoints.into_iter().flatten().flatten().collect();
+        if mountpoints.is_empty() {
+            mountpoints.extend(raw.mountpoint);
+        }
+        let mut children = raw.children;
+        // Partitions are connected however their disk is.
+        for child in children.iter_mut().flatten() {
+            if child.type_ == "part" && child.tran.is_none() {
+                child.tran.clone_from(&raw.tran);
+            }
+        }
+        Ok(LsblkBlockDevice {
+            children,
+            label: raw.label,
+            model: raw.model,
+            mountpoints,
+            serial: raw.serial,
+            path,
+            tran: raw.tran,
+            type_: raw.type_,
+            vendor: raw.vendor,
+            wwn: raw.wwn,
+            size: raw.size,
+            rota: raw.rota,
+        })
+    }
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        String(String),
+    }
+    match Option::<Size>::deserialize(deserializer)? {
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::String(size)) => parse_size(&size)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+fn deserialize_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Number(u8),
+        String(String),
+    }
+    match Option::<Flag>::deserialize(deserializer)? {
+        Some(Flag::Bool(value)) => Ok(Some(value)),
+        Some(Flag::Number(value)) => Ok(Some(value != 0)),
+        Some(Flag::String(value)) => Ok(Some(value.trim() != "0")),
+        None => Ok(None),
+    }
+}
+
+impl Selectable for LsblkBlockDevice {
+    fn field(&self, field: Field) -> FieldValue<'_> {
+        let string = match field {
+            Field::Model => &self.model,
+            Field::Serial => &self.serial,
+            Field::Wwn => &self.wwn,
+            Field::Tran => &self.tran,
+            Field::Vendor => &self.vendor,
+            Field::Size => return self.size.map_or(FieldValue::Missing, FieldValue::Size),
+            Field::Rota => return self.rota.map_or(FieldValue::Missing, FieldValue::Bool),
+        };
+        string
+            .as_deref()
+            .map_or(FieldValue::Missing, FieldValue::Str)
+    }
 }
 
 trait LsblkIteratorExt {
@@ -56,373 +251,3623 @@ impl<I: Iterator<Item = LsblkBlockDevice>> LsblkIteratorExt for I {
     }
 }
 
+/// Detected disks that share a model, size and transport.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiskGroup {
+    pub model: Option<String>,
+    /// Size of each disk in bytes.
+    pub size: Option<u64>,
+    pub transport: Option<String>,
+    pub devices: Vec<DiskInfo>,
+}
+
+/// A detected disk, as we can use it, and what we know about it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct DiskInfo {
+    /// Kernel device path, like `/dev/nvme1n1`.
+    pub path: String,
+    /// The disk's `/dev/disk/by-id` link, if udev made one.
+    pub by_id: Option<String>,
+    /// Size in bytes.
+    pub size: Option<u64>,
+    /// Model, without the padding some vendors add.
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    /// Firmware revision, when the disk was identified with the NVMe
+    /// Identify Controller command, like with `--aws-nvme-identify`.
+    pub firmware: Option<String>,
+    /// World wide name.
+    pub wwn: Option<String>,
+    /// How the disk is connected, like `nvme`, as lsblk reports it.
+    pub transport: Option<String>,
+    /// The NUMA node the disk's controller is attached to, if the kernel knows.
+    pub numa_node: Option<u32>,
+    /// Whether the host has to give the disk up first, with
+    /// [`DiskDetectorTrait::reclaim`], like a mounted OpenStack ephemeral disk.
+    pub reclaim: bool,
+}
+
+impl DiskInfo {
+    /// The path to set the disk up with: its stable link, if it has one.
+    pub fn stable_path(&self) -> &str {
+        self.by_id.as_deref().unwrap_or(&self.path)
+    }
+}
+
+impl fmt::Display for DiskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.by_id {
+            Some(by_id) => write!(f, "{} ({by_id})", self.path),
+            None => write!(f, "{}", self.path),
+        }
+    }
+}
+
+impl DiskGroup {
+    /// Kernel device paths of the disks.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.devices.iter().map(|disk| disk.path.as_str())
+    }
+
+    /// Orders groups by how much we prefer them:
+    /// the one with more disks, then the one with larger disks.
+    pub fn preference(&self) -> (usize, Option<u64>) {
+        (self.devices.len(), self.size)
+    }
+}
+
+impl fmt::Display for DiskGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} x '{}' of {} over {}: {:?}",
+            self.devices.len(),
+            self.model.as_deref().unwrap_or("unknown model"),
+            self.size
+                .map_or("unknown size".to_owned(), |size| format!("{size} bytes")),
+            self.transport.as_deref().unwrap_or(NO_TRANSPORT),
+            self.paths().collect::<Vec<_>>()
+        )
+    }
+}
+
+/// The devices a cloud provider detected.
+#[derive(Debug, Default, PartialEq)]
+struct ProviderDevices {
+    paths: Vec<String>,
+    /// Of those, the ones the host has to give up first,
+    /// because they're mounted or partitioned.
+    reclaim: Vec<String>,
+    /// What the NVMe Identify Controller command said about the ones
+    /// the provider identified with it.
+    identities: HashMap<String, Identity>,
+}
+
+impl From<Vec<String>> for ProviderDevices {
+    fn from(paths: Vec<String>) -> Self {
+        ProviderDevices {
+            paths,
+            ..Default::default()
+        }
+    }
+}
+
+/// A device's serial and firmware revision, from its controller's
+/// Identify Controller data.
+#[derive(Clone, Debug, PartialEq)]
+struct Identity {
+    serial: String,
+    firmware: String,
+}
+
+/// A detected disk whose NVMe SMART / Health log says it's failing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnhealthyDisk {
+    pub device: String,
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for UnhealthyDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}': {}", self.device, self.problems.join(", "))
+    }
+}
+
+/// The namespaces of one NVMe controller among the detected devices.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NvmeController {
+    /// The controller character device, like `/dev/nvme1`.
+    pub controller: String,
+    /// Its namespace block devices, in namespace ID order.
+    pub namespaces: Vec<String>,
+}
+
+impl fmt::Display for NvmeController {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "'{}' with {} namespaces: {:?}",
+            self.controller,
+            self.namespaces.len(),
+            self.namespaces
+        )
+    }
+}
+
+/// Why a block device isn't used.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ExclusionReason {
+    /// The host's root or boot filesystem, swap, or a RAID array is on it.
+    HostDisk,
+    Mounted,
+    HasChildren,
+    Transport,
+    NotDisk,
+    Selector,
+    /// The cloud provider's detection didn't pick it,
+    /// for example because it has the wrong model.
+    Provider,
+    ExcludeDevice,
+    NotIncluded,
+    /// The NVMe namespace policy dropped it.
+    Namespace,
+    /// Its NVMe SMART / Health log says it's failing.
+    Unhealthy,
+    DiskGroup,
+}
+
+struct Exclusion {
+    reason: ExclusionReason,
+    detail: String,
+}
+
+impl Exclusion {
+    fn new(reason: ExclusionReason, detail: impl Into<String>) -> Self {
+        Exclusion {
+            reason,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Every block device on the node, and whether detection would use it.
+#[derive(Debug, Serialize)]
+pub struct Inventory {
+    cloud_provider: String,
+    instance_type: Option<String>,
+    devices: Vec<InventoryDevice>,
+}
+
+#[derive(Debug, Serialize)]
+struct InventoryDevice {
+    path: String,
+    /// The device's most stable `/dev/disk/by-id` link, if it has one.
+    by_id: Option<String>,
+    /// The NVMe controller, if this is one of its namespaces.
+    controller: Option<String>,
+    namespace: Option<u32>,
+    selected: bool,
+    /// Whether the host has to give it up first, like a mounted ephemeral disk.
+    reclaim: bool,
+    /// Firmware revision, when the provider identified the device
+    /// with the NVMe Identify Controller command.
+    firmware: Option<String>,
+    reason: Option<ExclusionReason>,
+    detail: Option<String>,
+    attributes: LsblkBlockDevice,
+}
+
+/// What to do when the detected disks differ in model, size or transport.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiskGroupPolicy {
+    /// Use all of them.
+    #[default]
+    All,
+    /// Only use the group with the most disks, preferring larger disks on ties.
+    Largest,
+    /// Fail without configuring any devices.
+    Fail,
+}
+
+/// What to do when a detected disk's NVMe SMART / Health log says it's failing.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HealthPolicy {
+    /// Don't read the SMART / Health log.
+    Ignore,
+    /// Log a warning for each unhealthy disk, and use it anyway.
+    #[default]
+    Warn,
+    /// Don't use unhealthy disks.
+    Exclude,
+    /// Fail without configuring any devices.
+    Fail,
+}
+
+/// Which namespaces to use when NVMe controllers have more than one.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NamespacePolicy {
+    /// Use all of them.
+    #[default]
+    All,
+    /// Only use the namespace with the lowest ID on each controller.
+    First,
+    /// Use all of them, but fail without configuring any devices unless
+    /// every controller has the required number of namespaces.
+    Require,
+}
+
 pub trait DiskDetectorTrait {
-    fn detect_devices(&self) -> Vec<String>;
+    /// Detects devices, grouped by model, size and transport,
+    /// keeping the groups the grouping policy picks.
+    fn detect_device_groups(&self) -> Result<Vec<DiskGroup>, Error>;
+
+    /// Detects disks from all the groups the grouping policy picks.
+    fn detect_disks(&self) -> Result<Vec<DiskInfo>, Error> {
+        Ok(self
+            .detect_device_groups()?
+            .into_iter()
+            .flat_map(|group| group.devices)
+            .collect())
+    }
+
+    /// Detects the paths of disks from all the groups the grouping policy picks.
+    fn detect_devices(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .detect_disks()?
+            .into_iter()
+            .map(|disk| disk.path)
+            .collect())
+    }
+
+    /// Takes the detected disks marked for it back from the host,
+    /// right before they're set up: unmounts them, checks nothing else
+    /// is using them, then wipes them.
+    fn reclaim(&self, _disks: &[DiskInfo]) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 impl DiskDetectorTrait for DiskDetector {
-    fn detect_devices(&self) -> Vec<String> {
+    /// Detects devices, polling until the expected number have appeared
+    /// or the wait timeout is reached.
+    fn detect_device_groups(&self) -> Result<Vec<DiskGroup>, Error> {
+        match &self.options.instance_metadata {
+            Some(metadata) => info!(
+                "Detecting disks for cloud provider: {:?}, instance type: {}",
+                self.cloud_provider, metadata.instance_type
+            ),
+            None => info!(
+                "Detecting disks for cloud provider: {:?}",
+                self.cloud_provider
+            ),
+        }
+        let min_disks = self.options.min_disks.unwrap_or(1).max(1);
+        // Without an explicit count, wait for as many as the catalog says.
+        let expected_disks = self.options.expected_disks.or_else(|| {
+            let (instance_type, entry) = self.catalog_entry()?;
+            let count = entry.disks.exact()?;
+            info!(
+                "Expecting {count} devices for instance type {instance_type} from the disk catalog"
+            );
+            Some(count)
+        });
+        let wanted = expected_disks.unwrap_or(0).max(min_disks);
+        let start = Instant::now();
+        let (devices, provider_devices) = loop {
+            self.udevadm_settle();
+            let provider_devices = self.detect_provider_devices()?;
+            let devices = self.filter_devices(&provider_devices)?;
+            let waited = start.elapsed();
+            if devices.len() >= wanted || waited >= self.options.device_wait_timeout {
+                break (devices, provider_devices);
+            }
+            info!(
+                "Found {} of {wanted} devices {devices:?}, waiting up to {:?} for more",
+                devices.len(),
+                self.options.device_wait_timeout - waited
+            );
+            std::thread::sleep(DEVICE_POLL_INTERVAL.min(self.options.device_wait_timeout - waited));
+        };
+        let waited = start.elapsed();
+        let devices = self.select_namespaces(devices)?;
+        let devices = self.check_health(devices)?;
+        if devices.is_empty() {
+            return Err(Error::NoDevices { waited });
+        }
+        if devices.len() < min_disks {
+            return Err(Error::NotEnoughDevices {
+                found: devices,
+                min_disks,
+                waited,
+            });
+        }
+        if let Some(expected_disks) = expected_disks
+            && devices.len() != expected_disks
+        {
+            warn!(
+                "Expected {expected_disks} devices, but found {} after waiting {waited:?}. Continuing, since at least {min_disks} are required.",
+                devices.len()
+            );
+        }
+        let disks = self.describe_devices(devices)?;
+        self.check_catalog(&disks)?;
+        let mut groups = self.select_groups(group_disks(&disks))?;
+        let disk_ids = self.disk_ids();
+        for disk in groups.iter_mut().flat_map(|group| &mut group.devices) {
+            disk.by_id = disk_ids
+                .by_id(&disk.path)
+                .map(|by_id| self.options.host_root.device(by_id));
+            disk.numa_node = self.numa_node(&disk.path);
+            disk.reclaim = provider_devices.reclaim.contains(&disk.path);
+            if let Some(identity) = provider_devices.identities.get(&disk.path) {
+                disk.serial = Some(identity.serial.clone());
+                disk.firmware = Some(identity.firmware.clone());
+            }
+            disk.path = self.options.host_root.device(&disk.path);
+        }
         info!(
-            "Detecting disks for cloud provider: {:?}",
-            self.cloud_provider
+            "Found devices: {}",
+            groups
+                .iter()
+                .flat_map(|group| &group.devices)
+                .map(|disk| disk.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
         );
-        let devices = match self.cloud_provider {
-            CloudProvider::Aws => self.detect_aws_devices(),
-            CloudProvider::Gcp => self.detect_gcp_devices(),
-            CloudProvider::Azure => self.detect_azure_devices(),
-            CloudProvider::Generic => self.detect_generic_devices(),
+        Ok(groups)
+    }
+
+    fn reclaim(&self, disks: &[DiskInfo]) -> Result<(), Error> {
+        let disks: Vec<&DiskInfo> = disks.iter().filter(|disk| disk.reclaim).collect();
+        if disks.is_empty() {
+            return Ok(());
+        }
+        if self.cloud_provider == CloudProvider::Azure {
+            self.disable_waagent_resource_disk()?;
+        }
+        let devices = self.lsblk_all()?;
+        for disk in disks {
+            let device = devices
+                .iter()
+                .find(|device| self.options.host_root.device(&device.path) == disk.path)
+                .ok_or_else(|| Error::io(&disk.path)(std::io::ErrorKind::NotFound.into()))?;
+            self.reclaim_device(device)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why the host keeps us from using a device the provider detected, if it does.
+/// Devices we reclaim are taken back from the host's mounts.
+fn host_exclusion(
+    host_disks: &HostDisks,
+    provider_devices: &ProviderDevices,
+    device: &str,
+) -> Option<Exclusion> {
+    host_disks.exclusion(device).filter(|exclusion| {
+        exclusion.reason != ExclusionReason::Mounted
+            || !provider_devices.reclaim.iter().any(|path| path == device)
+    })
+}
+
+/// The group with the most disks, preferring larger disks,
+/// then the first group we found, on ties.
+fn largest_group(groups: &[DiskGroup]) -> &DiskGroup {
+    groups
+        .iter()
+        .rev()
+        .max_by_key(|group| group.preference())
+        .expect("there is at least one group")
+}
+
+/// Groups disks by model, size and transport, in the order we found them.
+fn group_disks(disks: &[LsblkBlockDevice]) -> Vec<DiskGroup> {
+    let mut groups: Vec<DiskGroup> = vec![];
+    for disk in disks {
+        // Vendors pad their model strings.
+        let model = disk.model.as_deref().map(|model| model.trim().to_owned());
+        let detected = DiskInfo {
+            path: disk.path.clone(),
+            by_id: None,
+            size: disk.size,
+            model: model.clone(),
+            serial: disk.serial.clone(),
+            firmware: None,
+            wwn: disk.wwn.clone(),
+            transport: disk.tran.clone(),
+            numa_node: None,
+            reclaim: false,
         };
-        if devices.is_empty() {
-            panic!("No suitable NVMe devices found");
+        match groups.iter_mut().find(|group| {
+            group.model == model && group.size == disk.size && group.transport == disk.tran
+        }) {
+            Some(group) => group.devices.push(detected),
+            None => groups.push(DiskGroup {
+                model,
+                size: disk.size,
+                transport: disk.tran.clone(),
+                devices: vec![detected],
+            }),
         }
-        info!("Found devices: {:?}", &devices);
-        devices
     }
+    groups
 }
 
-pub struct DiskDetector {
-    cloud_provider: CloudProvider,
-    commander: Commander,
+/// Groups NVMe namespaces by controller, in the order of each controller's
+/// first namespace. Devices that aren't NVMe namespaces are left out.
+fn nvme_controllers(devices: &[String]) -> Vec<NvmeController> {
+    let mut namespaces: Vec<(&str, u32, &String)> = devices
+        .iter()
+        .filter_map(|device| {
+            let (controller, id) = split_namespace(device)?;
+            Some((controller, id, device))
+        })
+        .collect();
+    namespaces.sort_by_key(|(_, id, _)| *id);
+    let mut controllers: Vec<NvmeController> = vec![];
+    for device in devices {
+        let Some((controller, _)) = split_namespace(device) else {
+            continue;
+        };
+        if controllers.iter().any(|c| c.controller == controller) {
+            continue;
+        }
+        controllers.push(NvmeController {
+            controller: controller.to_owned(),
+            namespaces: namespaces
+                .iter()
+                .filter(|(c, _, _)| *c == controller)
+                .map(|(_, _, device)| (*device).clone())
+                .collect(),
+        });
+    }
+    controllers
 }
 
 impl DiskDetector {
-    pub fn new(commander: Commander, cloud_provider: CloudProvider) -> Self {
-        DiskDetector {
-            cloud_provider,
-            commander,
+    fn detect_provider_devices(&self) -> Result<ProviderDevices, Error> {
+        match self.cloud_provider {
+            CloudProvider::Aws => self.detect_aws_devices(),
+            CloudProvider::Gcp => self.detect_gcp_devices().map(Into::into),
+            CloudProvider::Azure => self.detect_azure_devices(),
+            CloudProvider::Oci => self.detect_oci_devices().map(Into::into),
+            CloudProvider::Alibaba => self.detect_alibaba_devices().map(Into::into),
+            CloudProvider::OpenStack => self.detect_openstack_devices(),
+            CloudProvider::Generic => self.detect_generic_devices().map(Into::into),
+            CloudProvider::Auto => Err(Error::Unsupported(
+                "the cloud provider must be resolved before detecting disks".to_owned(),
+            )),
         }
     }
-    fn lsblk(&self) -> impl Iterator<Item = LsblkBlockDevice> {
-        let output = self
-            .commander
-            .check_output(&["lsblk", "--json", "--output-all"]);
-        let lsblk_blockdevices = serde_json::from_slice::<Lsblk>(&output.stdout)
-            .expect("Failed to deserialize output of 'lsblk --json --output-all'")
-            .blockdevices;
-        trace!(
-            "lsblk block devices:\n{}",
-            String::from_utf8_lossy(&output.stdout)
-        );
-        lsblk_blockdevices.into_iter().filter(|device| {
-            if device.mountpoint.is_some() {
-                debug!("Excluding device '{}' because it is mounted.", &device.path);
-                return false;
-            }
 
-            if device
-                .children
-                .as_ref()
-                .map(|children| !children.is_empty())
-                .unwrap_or(false)
-            {
-                debug!(
-                    "Excluding device '{}' because it has children.",
-                    &device.path
-                );
-                return false;
-            }
+    /// The catalog entry for the instance type we're running on, if any.
+    fn catalog_entry(&self) -> Option<(&str, &CatalogEntry)> {
+        if self.options.catalog_policy == CatalogPolicy::Ignore {
+            return None;
+        }
+        let instance_type = &self.options.instance_metadata.as_ref()?.instance_type;
+        let entry = self.options.catalog.get(self.cloud_provider, instance_type);
+        if entry.is_none() {
+            debug!("Instance type {instance_type} is not in the disk catalog");
+        }
+        Some((instance_type, entry?))
+    }
 
-            if device.tran.as_deref() != Some("nvme") {
-                debug!(
-                    "Excluding device '{}' because it is not connected by nvme.",
-                    &device.path
-                );
-                return false;
+    /// Drops the disks the host uses, then applies the include and exclude
+    /// device patterns on top of what the provider detected.
+    fn filter_devices(&self, provider_devices: &ProviderDevices) -> Result<Vec<String>, Error> {
+        let host_disks = self.host_disks();
+        let devices: Vec<String> = provider_devices
+            .paths
+            .iter()
+            .filter(
+                |device| match host_exclusion(&host_disks, provider_devices, device) {
+                    Some(exclusion) => {
+                        info!("Excluding device '{device}' because {}", exclusion.detail);
+                        false
+                    }
+                    None => true,
+                },
+            )
+            .cloned()
+            .collect();
+        if self.options.include_devices.is_empty() && self.options.exclude_devices.is_empty() {
+            return Ok(devices);
+        }
+        let pattern_exclusion = self.pattern_exclusion()?;
+        Ok(devices
+            .into_iter()
+            .filter(|device| match pattern_exclusion(device) {
+                Some(exclusion) => {
+                    info!("Excluding device '{device}' because {}", exclusion.detail);
+                    false
+                }
+                None => true,
+            })
+            .collect())
+    }
+
+    /// Resolves the include and exclude device patterns into a check
+    /// of why a device is filtered out by them, if it is.
+    fn pattern_exclusion(&self) -> Result<impl Fn(&str) -> Option<Exclusion>, Error> {
+        let resolve = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| Ok((pattern.clone(), self.resolve_device_pattern(pattern)?)))
+                .collect::<Result<Vec<_>, Error>>()
+        };
+        let include = resolve(&self.options.include_devices)?;
+        let exclude = resolve(&self.options.exclude_devices)?;
+        Ok(move |device: &str| {
+            if let Some((pattern, _)) = exclude.iter().find(|(_, matches)| matches(device)) {
+                return Some(Exclusion::new(
+                    ExclusionReason::ExcludeDevice,
+                    format!("it matches --exclude-device '{pattern}'"),
+                ));
             }
+            if include.is_empty() {
+                return None;
+            }
+            match include.iter().find(|(_, matches)| matches(device)) {
+                Some((pattern, _)) => {
+                    debug!(
+                        "Including device '{device}' because it matches --include-device '{pattern}'"
+                    );
+                    None
+                }
+                None => Some(Exclusion::new(
+                    ExclusionReason::NotIncluded,
+                    "it doesn't match any --include-device",
+                )),
+            }
+        })
+    }
 
-            if device.type_ != "disk" {
-                debug!(
-                    "Excluding device '{}' because its type is not disk.",
-                    &device.path
-                );
-                return false;
+    /// Turns a device path or glob into a check for device paths like `/dev/nvme1n1`.
+    /// Patterns under `/dev/disk`, like `/dev/disk/by-id/nvme-SAMSUNG*`
+    /// or `/dev/disk/by-*/nvme-SAMSUNG*`, are resolved to the devices
+    /// the host's links point to.
+    fn resolve_device_pattern(
+        &self,
+        pattern: &str,
+    ) -> Result<impl Fn(&str) -> bool + use<>, Error> {
+        let linked = match pattern.strip_prefix("/dev/disk/") {
+            Some(_) => {
+                let (dir, name) = pattern.rsplit_once('/').expect("pattern has a directory");
+                self.link_dirs(dir)
+                    .iter()
+                    .flat_map(|dir| self.find(dir, name))
+                    .collect()
             }
+            None => vec![],
+        };
+        let pattern = pattern.to_owned();
+        Ok(move |device: &str| glob_match(&pattern, device) || linked.iter().any(|d| d == device))
+    }
 
-            true
-        })
+    /// Expands globs in a host directory path, like `/dev/disk/by-*`,
+    /// to the directories there are.
+    fn link_dirs(&self, pattern: &str) -> Vec<String> {
+        let mut dirs = vec![String::new()];
+        for component in pattern.split('/').filter(|c| !c.is_empty()) {
+            dirs = dirs
+                .into_iter()
+                .flat_map(|dir| {
+                    if !component.contains(['*', '?']) {
+                        return vec![format!("{dir}/{component}")];
+                    }
+                    sysfs::list_dir(&self.options.host_root.path(&dir))
+                        .into_iter()
+                        .filter(|name| glob_match(component, name))
+                        .map(|name| format!("{dir}/{name}"))
+                        .collect()
+                })
+                .collect();
+        }
+        dirs
     }
 
-    fn find(&self, dir: &str, name: &str) -> Vec<String> {
-        let mut devices: Vec<String> = String::from_utf8_lossy(
-            &self
-                .commander
-                .check_output(&["find", dir, "-name", name])
-                .stdout,
-        )
-        .trim()
-        .lines()
-        // We only want full disks, not partitions on them.
-        .filter(|line| !line.contains("-part"))
-        .map(|line| {
-            // Get the device path without links,
-            // so we can later remove duplicates
-            // and compare with lsblk output.
-            #[cfg(not(test))]
-            return std::fs::canonicalize(line)
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned();
+    /// Looks up the attributes of each detected device.
+    fn describe_devices(&self, devices: Vec<String>) -> Result<Vec<LsblkBlockDevice>, Error> {
+        let mut all: HashMap<String, LsblkBlockDevice> = self
+            .lsblk_all()?
+            .into_iter()
+            .map(|device| (device.path.clone(), device))
+            .collect();
+        Ok(devices
+            .into_iter()
+            .map(|path| {
+                all.remove(&path).unwrap_or_else(|| LsblkBlockDevice {
+                    path,
+                    ..Default::default()
+                })
+            })
+            .collect())
+    }
 
-            #[cfg(test)]
-            {
-                let ordinal = line.chars().last().unwrap();
-                format!("/dev/nvme{ordinal}n1")
+    /// Compares the detected devices with the catalog,
+    /// failing or warning about differences depending on the policy.
+    fn check_catalog(&self, disks: &[LsblkBlockDevice]) -> Result<(), Error> {
+        let Some((instance_type, entry)) = self.catalog_entry() else {
+            return Ok(());
+        };
+        let devices: Vec<(String, Option<u64>)> = disks
+            .iter()
+            .map(|disk| (disk.path.clone(), disk.size))
+            .collect();
+        let mismatches = entry.compare(instance_type, &devices);
+        if mismatches.is_empty() {
+            info!("Devices match the disk catalog for instance type {instance_type}");
+            return Ok(());
+        }
+        if self.options.catalog_policy == CatalogPolicy::Fail {
+            return Err(Error::CatalogMismatch(mismatches));
+        }
+        for mismatch in &mismatches {
+            warn!("Devices don't match the disk catalog: {mismatch}");
+        }
+        Ok(())
+    }
+
+    /// Reads the NVMe SMART / Health log of each device, and fails,
+    /// warns about or drops the unhealthy ones depending on the policy.
+    fn check_health(&self, devices: Vec<String>) -> Result<Vec<String>, Error> {
+        if self.options.health_policy == HealthPolicy::Ignore {
+            return Ok(devices);
+        }
+        let mut health_problems = self.health_problems();
+        let mut healthy = vec![];
+        let mut unhealthy = vec![];
+        for device in devices {
+            match health_problems(&device) {
+                Some(problems) if !problems.is_empty() => {
+                    unhealthy.push(UnhealthyDisk { device, problems })
+                }
+                _ => healthy.push(device),
             }
-        })
-        .collect();
-        devices.sort();
-        devices.dedup();
-        trace!(
-            "found devices in {dir} matching name {name}:\n{:?}",
-            &devices
-        );
-        devices
+        }
+        if unhealthy.is_empty() {
+            return Ok(healthy);
+        }
+        match self.options.health_policy {
+            HealthPolicy::Ignore => unreachable!("health isn't checked with the ignore policy"),
+            HealthPolicy::Warn => {
+                for disk in &unhealthy {
+                    warn!("Using unhealthy device {disk}");
+                }
+                healthy.extend(unhealthy.into_iter().map(|disk| disk.device));
+                healthy.sort();
+                Ok(healthy)
+            }
+            HealthPolicy::Exclude => {
+                for disk in &unhealthy {
+                    warn!("Excluding unhealthy device {disk}");
+                }
+                Ok(healthy)
+            }
+            HealthPolicy::Fail => Err(Error::UnhealthyDisks(unhealthy)),
+        }
     }
 
-    fn detect_aws_devices(&self) -> Vec<String> {
-        if std::fs::exists(BOTTLEROCKET_ROOTFS_PATH).unwrap() {
-            self.detect_aws_bottlerocket_devices()
-        } else {
-            self.detect_aws_standard_devices()
+    /// Returns a check of what a device's SMART / Health log says is wrong
+    /// with it, if we can read it. Only NVMe devices have one, and it's
+    /// shared by all namespaces of a controller, so we read it once for each.
+    fn health_problems(&self) -> impl FnMut(&str) -> Option<Vec<String>> + '_ {
+        let mut controllers: HashMap<String, Option<Vec<String>>> = HashMap::new();
+        move |device: &str| {
+            let Some(controller) = controller_path(device) else {
+                debug!("Not checking the health of '{device}', since it isn't an NVMe namespace");
+                return None;
+            };
+            controllers
+                .entry(controller.to_owned())
+                .or_insert_with(|| match self.smart_log(controller) {
+                    Ok(smart_log) => {
+                        debug!("Controller '{controller}' has SMART / Health log {smart_log:?}");
+                        Some(smart_log.problems())
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Failed to read the SMART / Health log of '{controller}', assuming it's healthy: {e}"
+                        );
+                        None
+                    }
+                })
+                .clone()
         }
     }
 
-    fn detect_aws_bottlerocket_devices(&self) -> Vec<String> {
-        self.detect_aws_standard_devices()
-            .into_iter()
-            .map(|path| format!("{BOTTLEROCKET_ROOTFS_PATH}{path}"))
-            .collect()
+    /// Why the namespace policy drops each device it does.
+    fn namespace_exclusions(
+        &self,
+        devices: &[String],
+    ) -> Result<HashMap<String, Exclusion>, Error> {
+        let controllers = nvme_controllers(devices);
+        for controller in controllers.iter().filter(|c| c.namespaces.len() > 1) {
+            debug!("NVMe controller {controller}");
+        }
+        let mut exclusions = HashMap::new();
+        match self.options.namespace_policy {
+            NamespacePolicy::All => {}
+            NamespacePolicy::First => {
+                for controller in &controllers {
+                    for namespace in &controller.namespaces[1..] {
+                        exclusions.insert(
+                            namespace.clone(),
+                            Exclusion::new(
+                                ExclusionReason::Namespace,
+                                format!(
+                                    "it isn't the first namespace of controller '{}'",
+                                    controller.controller
+                                ),
+                            ),
+                        );
+                    }
+                }
+            }
+            NamespacePolicy::Require => {
+                let expected = self.required_namespaces()?;
+                for controller in &controllers {
+                    if controller.namespaces.len() == expected {
+                        continue;
+                    }
+                    for namespace in &controller.namespaces {
+                        exclusions.insert(
+                            namespace.clone(),
+                            Exclusion::new(
+                                ExclusionReason::Namespace,
+                                format!(
+                                    "controller '{}' has {} namespaces, but {expected} are required",
+                                    controller.controller,
+                                    controller.namespaces.len()
+                                ),
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        Ok(exclusions)
     }
-    fn detect_aws_standard_devices(&self) -> Vec<String> {
-        self.lsblk()
-            .filter_model("Amazon EC2 NVMe Instance Storage")
-            .paths()
-            .collect()
+
+    fn required_namespaces(&self) -> Result<usize, Error> {
+        self.options.namespaces_per_controller.ok_or_else(|| {
+            Error::Unsupported(
+                "the require NVMe namespace policy needs the number of namespaces per controller"
+                    .to_owned(),
+            )
+        })
     }
 
-    fn detect_gcp_devices(&self) -> Vec<String> {
-        // `lsblk` doesn't contain a descriptive model for
-        // GCP devices, so out of paranoia, we use `find` to
-        // filter to local SSDs. We don't only use `find`
-        // because the devices might have partitions or other
-        // children we need to filter out.
-        // All local disks will take the form of google-local-*.
-        // We'll make the assumption that the machine has homogeneous
-        // disk setup, and that the disks the user configured or are
-        // provided by the machine are NVME or equivilently fast.
-        let find_paths = self.find("/dev/disk/by-id", "google-local-*");
+    /// Drops namespaces depending on the policy,
+    /// or fails if the controllers don't have the required layout.
+    fn select_namespaces(&self, devices: Vec<String>) -> Result<Vec<String>, Error> {
+        let exclusions = self.namespace_exclusions(&devices)?;
+        if exclusions.is_empty() {
+            return Ok(devices);
+        }
+        if self.options.namespace_policy == NamespacePolicy::Require {
+            let expected = self.required_namespaces()?;
+            return Err(Error::NamespaceLayout {
+                expected,
+                controllers: nvme_controllers(&devices)
+                    .into_iter()
+                    .filter(|controller| controller.namespaces.len() != expected)
+                    .collect(),
+            });
+        }
+        Ok(devices
+            .into_iter()
+            .filter(|device| match exclusions.get(device) {
+                Some(exclusion) => {
+                    info!("Excluding device '{device}' because {}", exclusion.detail);
+                    false
+                }
+                None => true,
+            })
+            .collect())
+    }
 
-        self.lsblk()
-            .paths()
-            .filter(|path| find_paths.contains(path))
-            .collect()
+    fn disk_ids(&self) -> DiskIds {
+        DiskIds::read(&self.options.host_root)
     }
 
-    fn detect_azure_devices(&self) -> Vec<String> {
-        self.lsblk()
-            .filter_model("Microsoft NVMe Direct Disk")
-            .paths()
-            .collect()
+    fn host_disks(&self) -> HostDisks {
+        HostDisks::read(&self.options.host_root)
     }
 
-    fn detect_generic_devices(&self) -> Vec<String> {
-        self.lsblk().paths().collect()
+    /// The NUMA node of a device, given as a path like `/dev/nvme1n1`.
+    fn numa_node(&self, device: &str) -> Option<u32> {
+        let name = Path::new(device).file_name()?.to_str()?;
+        sysfs::numa_node(self.options.host_root.root(), name)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::CloudProvider;
-    use crate::detect::{DiskDetector, LsblkBlockDevice};
-    use crate::test::TestEnv;
+    fn smart_log(&self, controller: &str) -> std::io::Result<SmartLog> {
+        self.nvme_admin
+            .smart_log(Path::new(&self.options.host_root.device(controller)))
+    }
 
-    #[test]
-    fn test_lsblk_filters() {
-        let test_env = TestEnv::new();
-        let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Aws);
+    /// Picks which groups of disks to use, depending on the policy.
+    fn select_groups(&self, groups: Vec<DiskGroup>) -> Result<Vec<DiskGroup>, Error> {
+        for group in &groups {
+            info!("Disk group: {group}");
+        }
+        if groups.len() <= 1 {
+            return Ok(groups);
+        }
+        match self.options.disk_group_policy {
+            DiskGroupPolicy::All => {
+                warn!(
+                    "Using all {} disk groups, even though they differ in model, size or transport",
+                    groups.len()
+                );
+                Ok(groups)
+            }
+            DiskGroupPolicy::Largest => {
+                let largest = largest_group(&groups).clone();
+                info!("Using the largest disk group: {largest}");
+                Ok(vec![largest])
+            }
+            DiskGroupPolicy::Fail => Err(Error::HeterogeneousDisks(groups)),
+        }
+    }
+
+    /// Waits for udev to finish processing events, so that new devices
+    /// and their links are in place before we look for them.
+    fn udevadm_settle(&self) {
+        match self.commander.try_output(&["udevadm", "settle"]) {
+            Ok(output) if !output.status.success() => warn!(
+                "'udevadm settle' failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                trace!("udevadm not available, not waiting for udev to settle");
+            }
+            Err(e) => warn!("Failed to run 'udevadm settle': {e}"),
+        }
+    }
+}
+
+/// Where we get the list of block devices from.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockDeviceSource {
+    /// Run `lsblk --json --output-all`.
+    #[default]
+    Lsblk,
+    /// Read /sys/block and /proc/self/mountinfo directly,
+    /// falling back to lsblk if sysfs can't be read.
+    /// Filesystem labels are not available this way.
+    Sysfs,
+}
+
+/// Provider specific knobs for disk detection.
+#[derive(Clone, Debug, Default)]
+pub struct DetectOptions {
+    /// Unmount and wipe the OpenStack ephemeral disk if it is mounted,
+    /// so it can be used.
+    pub openstack_reclaim_ephemeral: bool,
+    /// Only use devices matching this selector.
+    /// With the generic provider, this also replaces the NVMe transport check.
+    pub selector: Option<Selector>,
+    /// Where to get the list of block devices from.
+    pub block_device_source: BlockDeviceSource,
+    /// Identify AWS instance store volumes with the NVMe Identify Controller
+    /// command, rather than by their model string.
+    pub aws_nvme_identify: bool,
+    /// Transports devices may be connected by, as reported by lsblk,
+    /// overriding the cloud provider's default.
+    /// `none` matches devices lsblk reports without a transport.
+    pub transports: Option<Vec<String>>,
+    /// Unmount the Azure temporary resource disk and stop waagent from
+    /// remounting it, so it can be used when there are no NVMe disks.
+    pub azure_reclaim_resource_disk: bool,
+    /// Wait for this many devices to appear.
+    /// If fewer than this appear before the timeout,
+    /// we still continue with at least `min_disks`.
+    pub expected_disks: Option<usize>,
+    /// Fail if fewer than this many devices appear before the timeout.
+    /// Defaults to 1.
+    pub min_disks: Option<usize>,
+    /// How long to wait for devices to appear.
+    pub device_wait_timeout: Duration,
+    /// The instance we're running on, if the metadata service told us.
+    pub instance_metadata: Option<InstanceMetadata>,
+    /// Local disks we expect for each instance type.
+    pub catalog: Catalog,
+    /// What to do when the detected devices don't match the catalog.
+    pub catalog_policy: CatalogPolicy,
+    /// What to do when the detected devices differ in model, size or transport.
+    pub disk_group_policy: DiskGroupPolicy,
+    /// What to do when a detected device's SMART / Health log says it's failing.
+    pub health_policy: HealthPolicy,
+    /// Which namespaces to use when NVMe controllers have more than one.
+    pub namespace_policy: NamespacePolicy,
+    /// How many namespaces each NVMe controller must have,
+    /// with the require namespace policy.
+    pub namespaces_per_controller: Option<usize>,
+    /// Only use detected devices matching one of these paths or globs,
+    /// if there are any.
+    pub include_devices: Vec<String>,
+    /// Never use detected devices matching any of these paths or globs.
+    pub exclude_devices: Vec<String>,
+    /// Where the host's filesystem is, for device nodes, sysfs, and config files.
+    pub host_root: HostRoot,
+}
+
+/// The transports each cloud provider attaches its local disks with.
+fn default_transports(cloud_provider: CloudProvider) -> &'static [&'static str] {
+    match cloud_provider {
+        // Older GCP machine types attach local SSDs over virtio-scsi,
+        // which lsblk reports without a transport, or as virtio.
+        // We only use the ones with a google-local-* link anyway.
+        CloudProvider::Gcp => &["nvme", "virtio", NO_TRANSPORT],
+        // Nova attaches ephemeral disks over virtio-blk, which lsblk
+        // reports without a transport. We only use ephemeral disks anyway.
+        CloudProvider::OpenStack => &["nvme", "virtio", NO_TRANSPORT],
+        // The resource disk is attached over Hyper-V SCSI, which lsblk
+        // reports without a transport. Otherwise, we only use NVMe direct disks.
+        CloudProvider::Azure => &["nvme", NO_TRANSPORT],
+        _ => &["nvme"],
+    }
+}
+
+/// Builds a `DiskDetector`, for using detection as a library.
+/// Anything not set keeps its default from `DetectOptions`.
+#[derive(Clone)]
+pub struct DiskDetectorBuilder {
+    cloud_provider: CloudProvider,
+    commander: Commander,
+    options: DetectOptions,
+}
+
+impl DiskDetectorBuilder {
+    /// Runs commands like lsblk with this commander.
+    pub fn commander(mut self, commander: Commander) -> Self {
+        self.commander = commander;
+        self
+    }
+
+    /// Replaces all the options at once.
+    pub fn options(mut self, options: DetectOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn host_root(mut self, host_root: HostRoot) -> Self {
+        self.options.host_root = host_root;
+        self
+    }
+
+    pub fn instance_metadata(mut self, instance_metadata: InstanceMetadata) -> Self {
+        self.options.instance_metadata = Some(instance_metadata);
+        self
+    }
+
+    pub fn block_device_source(mut self, block_device_source: BlockDeviceSource) -> Self {
+        self.options.block_device_source = block_device_source;
+        self
+    }
+
+    pub fn selector(mut self, selector: Selector) -> Self {
+        self.options.selector = Some(selector);
+        self
+    }
+
+    pub fn transports(mut self, transports: Vec<String>) -> Self {
+        self.options.transports = Some(transports);
+        self
+    }
+
+    pub fn include_devices(mut self, include_devices: Vec<String>) -> Self {
+        self.options.include_devices = include_devices;
+        self
+    }
+
+    pub fn exclude_devices(mut self, exclude_devices: Vec<String>) -> Self {
+        self.options.exclude_devices = exclude_devices;
+        self
+    }
+
+    pub fn expected_disks(mut self, expected_disks: usize) -> Self {
+        self.options.expected_disks = Some(expected_disks);
+        self
+    }
+
+    pub fn min_disks(mut self, min_disks: usize) -> Self {
+        self.options.min_disks = Some(min_disks);
+        self
+    }
+
+    pub fn device_wait_timeout(mut self, device_wait_timeout: Duration) -> Self {
+        self.options.device_wait_timeout = device_wait_timeout;
+        self
+    }
+
+    pub fn catalog(mut self, catalog: Catalog, catalog_policy: CatalogPolicy) -> Self {
+        self.options.catalog = catalog;
+        self.options.catalog_policy = catalog_policy;
+        self
+    }
+
+    pub fn disk_group_policy(mut self, disk_group_policy: DiskGroupPolicy) -> Self {
+        self.options.disk_group_policy = disk_group_policy;
+        self
+    }
+
+    pub fn health_policy(mut self, health_policy: HealthPolicy) -> Self {
+        self.options.health_policy = health_policy;
+        self
+    }
+
+    /// The namespace policy, and how many namespaces each controller must
+    /// have with the require policy.
+    pub fn namespace_policy(
+        mut self,
+        namespace_policy: NamespacePolicy,
+        namespaces_per_controller: Option<usize>,
+    ) -> Self {
+        self.options.namespace_policy = namespace_policy;
+        self.options.namespaces_per_controller = namespaces_per_controller;
+        self
+    }
+
+    pub fn aws_nvme_identify(mut self, aws_nvme_identify: bool) -> Self {
+        self.options.aws_nvme_identify = aws_nvme_identify;
+        self
+    }
+
+    /// Fails for `CloudProvider::Auto`, which has to be resolved first,
+    /// with `CloudProvider::resolve`.
+    pub fn build(self) -> Result<DiskDetector, Error> {
+        if self.cloud_provider == CloudProvider::Auto {
+            return Err(Error::Unsupported(
+                "the cloud provider must be resolved before detecting disks".to_owned(),
+            ));
+        }
+        Ok(DiskDetector::with_options(
+            self.commander,
+            self.cloud_provider,
+            self.options,
+        ))
+    }
+}
+
+pub struct DiskDetector {
+    cloud_provider: CloudProvider,
+    commander: Commander,
+    options: DetectOptions,
+    // Tests stand in for the NVMe controllers.
+    nvme_admin: Arc<dyn NvmeAdmin>,
+    // Where the sysfs block device source reads mountpoints from.
+    // Tests stand in for our own mount table too.
+    mountinfo: PathBuf,
+}
+
+impl DiskDetector {
+    /// Doesn't check the cloud provider, so outside the crate,
+    /// detectors are made with [`DiskDetector::builder`].
+    pub(crate) fn with_options(
+        commander: Commander,
+        cloud_provider: CloudProvider,
+        options: DetectOptions,
+    ) -> Self {
+        DiskDetector {
+            cloud_provider,
+            commander,
+            options,
+            nvme_admin: Arc::new(IoctlNvmeAdmin),
+            mountinfo: PathBuf::from(sysfs::MOUNTINFO_PATH),
+        }
+    }
+
+    /// Starts building a detector for a cloud provider,
+    /// with the default options.
+    pub fn builder(cloud_provider: CloudProvider) -> DiskDetectorBuilder {
+        DiskDetectorBuilder {
+            cloud_provider,
+            commander: Commander::default(),
+            options: DetectOptions::default(),
+        }
+    }
+
+    /// Lists every block device, and whether detection would use it,
+    /// without waiting for devices or changing anything.
+    pub fn inventory(&self) -> Result<Inventory, Error> {
+        let cloud_provider = self
+            .cloud_provider
+            .to_possible_value()
+            .expect("cloud providers have names")
+            .get_name()
+            .to_owned();
+        self.udevadm_settle();
+        let provider_devices = self.detect_provider_devices()?;
+        // Why each of the provider's devices is dropped by the later stages.
+        let mut exclusions = HashMap::new();
+        let host_disks = self.host_disks();
+        let pattern_exclusion = self.pattern_exclusion()?;
+        let mut remaining = vec![];
+        for device in &provider_devices.paths {
+            match host_exclusion(&host_disks, &provider_devices, device)
+                .or_else(|| pattern_exclusion(device))
+            {
+                Some(exclusion) => {
+                    exclusions.insert(device.clone(), exclusion);
+                }
+                None => remaining.push(device.clone()),
+            }
+        }
+        exclusions.extend(self.namespace_exclusions(&remaining)?);
+        remaining.retain(|device| !exclusions.contains_key(device));
+        if matches!(
+            self.options.health_policy,
+            HealthPolicy::Exclude | HealthPolicy::Fail
+        ) {
+            let mut health_problems = self.health_problems();
+            for device in &remaining {
+                if let Some(problems) = health_problems(device)
+                    && !problems.is_empty()
+                {
+                    exclusions.insert(
+                        device.clone(),
+                        Exclusion::new(ExclusionReason::Unhealthy, problems.join(", ")),
+                    );
+                }
+            }
+            remaining.retain(|device| !exclusions.contains_key(device));
+        }
+        let groups = group_disks(&self.describe_devices(remaining)?);
+        let (selected, group_detail): (Vec<&str>, _) = match self.options.disk_group_policy {
+            _ if groups.len() <= 1 => (groups.iter().flat_map(|g| g.paths()).collect(), ""),
+            DiskGroupPolicy::All => (groups.iter().flat_map(|g| g.paths()).collect(), ""),
+            DiskGroupPolicy::Largest => (
+                largest_group(&groups).paths().collect(),
+                "it isn't in the largest disk group",
+            ),
+            DiskGroupPolicy::Fail => (
+                vec![],
+                "the devices differ in model, size or transport, and the disk group policy is fail",
+            ),
+        };
+
+        let disk_ids = self.disk_ids();
+        let mut devices = vec![];
+        let mut pending = self.lsblk_all()?;
+        pending.reverse();
+        while let Some(mut device) = pending.pop() {
+            let exclusion = if provider_devices.paths.contains(&device.path) {
+                exclusions.remove(&device.path).or_else(|| {
+                    (!selected.contains(&device.path.as_str()))
+                        .then(|| Exclusion::new(ExclusionReason::DiskGroup, group_detail))
+                })
+            } else {
+                self.exclusion(&device, &host_disks).or_else(|| {
+                    Some(Exclusion::new(
+                        ExclusionReason::Provider,
+                        format!("the {cloud_provider} cloud provider doesn't use it"),
+                    ))
+                })
+            };
+            let children = device.children.take().unwrap_or_default();
+            pending.extend(children.into_iter().rev());
+            let namespace = split_namespace(&device.path);
+            devices.push(InventoryDevice {
+                path: device.path.clone(),
+                by_id: disk_ids.by_id(&device.path).map(str::to_owned),
+                controller: namespace.map(|(controller, _)| controller.to_owned()),
+                namespace: namespace.map(|(_, id)| id),
+                selected: exclusion.is_none(),
+                reclaim: exclusion.is_none() && provider_devices.reclaim.contains(&device.path),
+                firmware: provider_devices
+                    .identities
+                    .get(&device.path)
+                    .map(|identity| identity.firmware.clone()),
+                reason: exclusion.as_ref().map(|exclusion| exclusion.reason),
+                detail: exclusion.map(|exclusion| exclusion.detail),
+                attributes: device,
+            });
+        }
+        Ok(Inventory {
+            cloud_provider,
+            instance_type: self
+                .options
+                .instance_metadata
+                .as_ref()
+                .map(|metadata| metadata.instance_type.clone()),
+            devices,
+        })
+    }
+
+    /// All block devices, without any filtering.
+    fn lsblk_all(&self) -> Result<Vec<LsblkBlockDevice>, Error> {
+        if self.options.block_device_source == BlockDeviceSource::Sysfs {
+            let root = self.options.host_root.root();
+            match sysfs::block_devices(root, &self.mountinfo) {
+                Ok(devices) => return Ok(devices),
+                Err(e) => warn!(
+                    "Failed to read block devices from sysfs at '{}', falling back to lsblk: {e}",
+                    root.display()
+                ),
+            }
+        }
+
+        let output =
+            self.commander
+                .check_output(&["lsblk", "--json", "--output-all", "--bytes"])?;
+        trace!(
+            "lsblk block devices:\n{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        Ok(serde_json::from_slice::<Lsblk>(&output.stdout)
+            .map_err(Error::parse(
+                "output of 'lsblk --json --output-all --bytes'",
+            ))?
+            .blockdevices)
+    }
+
+    fn lsblk(&self) -> Result<impl Iterator<Item = LsblkBlockDevice> + '_, Error> {
+        let host_disks = self.host_disks();
+        Ok(self.lsblk_all()?.into_iter().filter(move |device| {
+            match self.exclusion(device, &host_disks) {
+                Some(exclusion) => {
+                    debug!(
+                        "Excluding device '{}' because {}.",
+                        &device.path, exclusion.detail
+                    );
+                    false
+                }
+                None => true,
+            }
+        }))
+    }
+
+    /// Why a device can't be used by any provider, if it can't.
+    fn exclusion(&self, device: &LsblkBlockDevice, host_disks: &HostDisks) -> Option<Exclusion> {
+        if let Some(exclusion) = host_disks.exclusion(&device.path) {
+            return Some(exclusion);
+        }
+
+        if !device.mountpoints.is_empty() {
+            return Some(Exclusion::new(ExclusionReason::Mounted, "it is mounted"));
+        }
+
+        if device
+            .children
+            .as_ref()
+            .map(|children| !children.is_empty())
+            .unwrap_or(false)
+        {
+            return Some(Exclusion::new(
+                ExclusionReason::HasChildren,
+                "it has children",
+            ));
+        }
+
+        self.attribute_exclusion(device)
+    }
+
+    /// Why a device can't be used by any provider because of what it is,
+    /// rather than how it's being used, if it can't.
+    fn attribute_exclusion(&self, device: &LsblkBlockDevice) -> Option<Exclusion> {
+        if !self.transport_allowed(device) {
+            return Some(Exclusion::new(
+                ExclusionReason::Transport,
+                format!(
+                    "its transport '{}' is not one of {:?}",
+                    device.tran.as_deref().unwrap_or(NO_TRANSPORT),
+                    self.allowed_transports(),
+                ),
+            ));
+        }
+
+        if device.type_ != "disk" {
+            return Some(Exclusion::new(
+                ExclusionReason::NotDisk,
+                "its type is not disk",
+            ));
+        }
+
+        if let Some(selector) = &self.options.selector
+            && !selector.matches(device)
+        {
+            return Some(Exclusion::new(
+                ExclusionReason::Selector,
+                format!("it doesn't match selector '{selector}'"),
+            ));
+        }
+
+        None
+    }
+
+    fn allowed_transports(&self) -> Vec<&str> {
+        match &self.options.transports {
+            Some(transports) => transports.iter().map(String::as_str).collect(),
+            None => default_transports(self.cloud_provider).to_vec(),
+        }
+    }
+
+    fn transport_allowed(&self, device: &LsblkBlockDevice) -> bool {
+        // Operators using a selector with the generic provider
+        // describe the transports they want themselves,
+        // unless they also gave us an explicit list.
+        if self.options.selector.is_some()
+            && self.cloud_provider == CloudProvider::Generic
+            && self.options.transports.is_none()
+        {
+            return true;
+        }
+        let tran = device.tran.as_deref().unwrap_or(NO_TRANSPORT);
+        self.allowed_transports()
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(tran))
+    }
+
+    /// Finds the devices that host links in a directory like
+    /// `/dev/disk/by-id` point to, for links with names matching a glob.
+    fn find(&self, dir: &str, name: &str) -> Vec<String> {
+        let host_root = &self.options.host_root;
+        let mut devices: Vec<String> = sysfs::list_dir(&host_root.path(dir))
+            .into_iter()
+            // We only want full disks, not partitions on them.
+            .filter(|link| glob_match(name, link) && !link.contains("-part"))
+            .filter_map(|link| {
+                // Get the device path without links,
+                // so we can later remove duplicates
+                // and compare with lsblk output.
+                let link = format!("{dir}/{link}");
+                host_root
+                    .resolve_link(&link)
+                    .inspect_err(|e| debug!("Failed to resolve link '{link}': {e}"))
+                    .ok()
+            })
+            .collect();
+        devices.sort();
+        devices.dedup();
+        trace!(
+            "found devices in {dir} matching name {name}:\n{:?}",
+            &devices
+        );
+        devices
+    }
+
+    fn detect_aws_devices(&self) -> Result<ProviderDevices, Error> {
+        if self.options.aws_nvme_identify {
+            return self.detect_aws_identify_devices();
+        }
+        Ok(self
+            .lsblk()?
+            .filter_model("Amazon EC2 NVMe Instance Storage")
+            .paths()
+            .collect::<Vec<_>>()
+            .into())
+    }
+
+    fn detect_aws_identify_devices(&self) -> Result<ProviderDevices, Error> {
+        let mut provider_devices = ProviderDevices::default();
+        for device in self.lsblk()? {
+            let identify = controller_path(&device.path)
+                .ok_or_else(|| std::io::Error::other("not an NVMe namespace"))
+                .and_then(|controller| self.identify_controller(controller));
+            let identify = match identify {
+                Ok(identify) => identify,
+                Err(e) => {
+                    warn!(
+                        "Excluding device '{}' because we failed to identify its controller: {e}",
+                        &device.path
+                    );
+                    continue;
+                }
+            };
+            info!(
+                "Device '{}' has model '{}', serial '{}', firmware '{}'",
+                &device.path, identify.model, identify.serial, identify.firmware
+            );
+            if !identify.is_aws_instance_store() {
+                debug!(
+                    "Excluding device '{}' because it is not instance store.",
+                    &device.path
+                );
+                continue;
+            }
+            provider_devices.paths.push(device.path.clone());
+            provider_devices.identities.insert(
+                device.path.clone(),
+                Identity {
+                    serial: identify.serial,
+                    firmware: identify.firmware,
+                },
+            );
+        }
+        Ok(provider_devices)
+    }
+
+    fn identify_controller(&self, controller: &str) -> std::io::Result<IdentifyController> {
+        self.nvme_admin
+            .identify_controller(Path::new(&self.options.host_root.device(controller)))
+    }
+
+    fn detect_gcp_devices(&self) -> Result<Vec<String>, Error> {
+        // `lsblk` doesn't contain a descriptive model for
+        // GCP devices, so out of paranoia, we use udev links to
+        // filter to local SSDs. We don't only use the links
+        // because the devices might have partitions or other
+        // children we need to filter out.
+        // All local disks will take the form of google-local-*,
+        // google-local-nvme-ssd-N for NVMe and google-local-ssd-N for SCSI.
+        // We'll make the assumption that the machine has homogeneous
+        // disk setup, and that the disks the user configured or are
+        // provided by the machine are NVME or equivilently fast.
+        // Without udev, like when the host's /dev is bind mounted without
+        // its links, or on minimal images, we fall back to the attributes
+        // local SSDs have.
+        let find_paths = self.find(GCP_DISK_LINKS_DIR, "google-local-*");
+        if find_paths.is_empty() && !self.has_gcp_disk_links() {
+            info!(
+                "No google-* links in '{GCP_DISK_LINKS_DIR}', identifying local SSDs by their model, serial and size"
+            );
+            return self.detect_gcp_devices_by_attributes();
+        }
+
+        Ok(self
+            .lsblk()?
+            .paths()
+            .filter(|path| find_paths.contains(path))
+            .collect())
+    }
+
+    /// Whether udev made links for any GCP disks,
+    /// in which case missing local SSD links mean there aren't any.
+    fn has_gcp_disk_links(&self) -> bool {
+        !self.find(GCP_DISK_LINKS_DIR, "google-*").is_empty()
+    }
+
+    /// Finds local SSDs without their udev links: they have a local SSD
+    /// model, a serial that isn't a persistent disk's, and the local SSD size.
+    fn detect_gcp_devices_by_attributes(&self) -> Result<Vec<String>, Error> {
+        let catalog_entry = self.catalog_entry().map(|(_, entry)| entry);
+        Ok(self
+            .lsblk()?
+            .filter(|device| {
+                let model = device.model.as_deref().unwrap_or_default().trim();
+                // NVMe local SSDs are `nvme_card`, or `local-nvme-ssd-N` on some
+                // images, and SCSI ones are `local-ssd-N`.
+                let serial = device.serial.as_deref().unwrap_or_default().trim();
+                let serial_matches = serial.is_empty()
+                    || serial == "nvme_card"
+                    || serial.starts_with("local-");
+                let size_matches = device.size.is_some_and(|size| match catalog_entry {
+                    Some(entry) => entry.size_matches(size),
+                    None => size == GCP_LOCAL_SSD_SIZE,
+                });
+                let is_local_ssd =
+                    GCP_LOCAL_SSD_MODELS.contains(&model) && serial_matches && size_matches;
+                if is_local_ssd {
+                    info!(
+                        "Device '{}' looks like a local SSD, with model '{model}', serial '{serial}' and size {:?}",
+                        device.path, device.size
+                    );
+                } else {
+                    debug!(
+                        "Excluding device '{}' with model '{model}', serial '{serial}' and size {:?}, because it doesn't look like a local SSD",
+                        device.path, device.size
+                    );
+                }
+                is_local_ssd
+            })
+            .paths()
+            .collect())
+    }
+
+    fn detect_azure_devices(&self) -> Result<ProviderDevices, Error> {
+        let devices: Vec<String> = self
+            .lsblk()?
+            .filter_model("Microsoft NVMe Direct Disk")
+            .paths()
+            .collect();
+        if devices.is_empty() && self.options.azure_reclaim_resource_disk {
+            debug!("No NVMe direct disks found, looking for the resource disk");
+            return self.detect_azure_resource_disk();
+        }
+        Ok(devices.into())
+    }
+
+    /// Finds the Azure temporary resource disk. It's always marked for
+    /// reclaim, since the Azure Linux agent has to give it up first.
+    fn detect_azure_resource_disk(&self) -> Result<ProviderDevices, Error> {
+        let (_, conf) = self.read_waagent_conf();
+        let mountpoint = waagent_conf_value(&conf, "ResourceDisk.MountPoint").unwrap_or("/mnt");
+
+        let devices = self.lsblk_all()?;
+        let linked = match self
+            .options
+            .host_root
+            .resolve_link(AZURE_RESOURCE_DISK_LINK)
+        {
+            // Partitions too, so we notice if it points at one.
+            Ok(link_target) => devices
+                .iter()
+                .flat_map(|device| [device].into_iter().chain(device.children.iter().flatten()))
+                .find(|device| device.path == link_target),
+            Err(e) => {
+                debug!("Failed to resolve '{AZURE_RESOURCE_DISK_LINK}': {e}");
+                None
+            }
+        };
+        let resource_disk = linked.or_else(|| {
+                // Without the Azure udev rules, the model is all we have,
+                // and the OS and data disks share it. The resource disk is
+                // the one waagent mounted, or still has its original label.
+                debug!(
+                    "'{AZURE_RESOURCE_DISK_LINK}' not found, looking for a '{AZURE_VIRTUAL_DISK_MODEL}' mounted at '{mountpoint}'"
+                );
+                devices.iter().find(|device| {
+                    device.type_ == "disk"
+                        && device
+                            .model
+                            .as_deref()
+                            .is_some_and(|model| model.trim() == AZURE_VIRTUAL_DISK_MODEL)
+                        && device.children.iter().flatten().any(|child| {
+                            child.label.as_deref() == Some(AZURE_RESOURCE_DISK_LABEL)
+                                || child.mountpoints.iter().any(|child_mountpoint| {
+                                    // We usually see the host's mounts under the host root.
+                                    child_mountpoint == mountpoint
+                                        || Path::new(child_mountpoint)
+                                            == self.options.host_root.path(mountpoint)
+                                })
+                        })
+                })
+        });
+        let Some(resource_disk) = resource_disk else {
+            warn!("No Azure resource disk found");
+            return Ok(ProviderDevices::default());
+        };
+        // The link could point at a partition, or something we'd never use.
+        if let Some(exclusion) = self.attribute_exclusion(resource_disk) {
+            warn!(
+                "Not using Azure resource disk '{}' because {}",
+                &resource_disk.path, exclusion.detail
+            );
+            return Ok(ProviderDevices::default());
+        }
+        info!("Found Azure resource disk '{}'", &resource_disk.path);
+        Ok(ProviderDevices {
+            paths: vec![resource_disk.path.clone()],
+            reclaim: vec![resource_disk.path.clone()],
+            ..Default::default()
+        })
+    }
+
+    /// Reads the Azure Linux agent's config from the host,
+    /// which is empty if it can't be read.
+    fn read_waagent_conf(&self) -> (PathBuf, String) {
+        let conf_path = self.options.host_root.path(AZURE_WAAGENT_CONF_PATH);
+        let conf = std::fs::read_to_string(&conf_path).unwrap_or_else(|e| {
+            debug!(
+                "Failed to read waagent config '{}': {e}",
+                conf_path.display()
+            );
+            String::new()
+        });
+        (conf_path, conf)
+    }
+
+    /// Stops waagent from formatting, mounting, and putting a swap file on
+    /// the resource disk, and turns off any swap file it already made.
+    fn disable_waagent_resource_disk(&self) -> Result<(), Error> {
+        let (conf_path, conf) = self.read_waagent_conf();
+        if conf.is_empty() {
+            return Ok(());
+        }
+        let mountpoint = waagent_conf_value(&conf, "ResourceDisk.MountPoint").unwrap_or("/mnt");
+        if waagent_conf_value(&conf, "ResourceDisk.EnableSwap") == Some("y") {
+            let swapfile = format!("{mountpoint}/swapfile");
+            info!("Turning off waagent swap file '{swapfile}' on the host");
+            self.commander.unchecked_output(&[
+                "nsenter", "--target", "1", "--mount", "--", "swapoff", &swapfile,
+            ])?;
+        }
+        let mut changed = false;
+        let mut updated: String = conf
+            .lines()
+            .map(|line| match line.trim().split_once('=') {
+                Some((key @ ("ResourceDisk.Format" | "ResourceDisk.EnableSwap"), value))
+                    if value.trim() != "n" =>
+                {
+                    changed = true;
+                    format!("{key}=n")
+                }
+                _ => line.to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        updated.push('\n');
+        if changed {
+            info!(
+                "Disabling the resource disk in waagent config '{}'",
+                conf_path.display()
+            );
+            std::fs::write(&conf_path, updated).map_err(Error::io(&conf_path))?;
+        }
+        Ok(())
+    }
+
+    fn detect_oci_devices(&self) -> Result<Vec<String>, Error> {
+        // Local NVMe disks on DenseIO shapes are passed through from the host,
+        // so they report the model of the drive itself.
+        // Block volumes identify themselves as an ORACLE BlockVolume,
+        // whether they are attached over iSCSI, paravirtualized, or as NVMe.
+        // The iSCSI and paravirtualized ones are already excluded by transport.
+        Ok(self
+            .lsblk()?
+            .filter(|device| {
+                let model = device.model.as_deref().unwrap_or_default().trim();
+                let is_block_volume = model.contains("BlockVolume")
+                    || device
+                        .vendor
+                        .as_deref()
+                        .is_some_and(|vendor| vendor.trim() == "ORACLE");
+                if is_block_volume {
+                    debug!(
+                        "Excluding device '{}' because it is an OCI block volume.",
+                        &device.path
+                    );
+                    return false;
+                }
+                let is_local = OCI_DENSE_IO_MODEL_PREFIXES
+                    .iter()
+                    .any(|prefix| model.starts_with(prefix));
+                if !is_local {
+                    warn!(
+                        "Excluding device '{}' with model '{model}', because it isn't a known DenseIO local NVMe disk.",
+                        &device.path
+                    );
+                }
+                is_local
+            })
+            .paths()
+            .collect())
+    }
+
+    fn detect_alibaba_devices(&self) -> Result<Vec<String>, Error> {
+        // On newer instance families, ESSD cloud disks are attached over NVMe
+        // just like the local disks, so transport alone doesn't tell them apart.
+        // Cloud disks usually report an "Alibaba Cloud Elastic Block Storage"
+        // model, but not on every family, so we also check the serial,
+        // which for cloud disks is the disk ID, usually without its "d-" prefix.
+        // Anything else has to be a known local disk model.
+        Ok(self
+            .lsblk()?
+            .filter(|device| {
+                let model = device.model.as_deref().unwrap_or_default().trim();
+                let is_cloud_disk = model.contains("Elastic Block Storage")
+                    || device
+                        .serial
+                        .as_deref()
+                        .is_some_and(is_alibaba_cloud_disk_serial);
+                if is_cloud_disk {
+                    debug!(
+                        "Excluding device '{}' because it is an Alibaba cloud disk.",
+                        &device.path
+                    );
+                    return false;
+                }
+                let is_local = ALIBABA_LOCAL_DISK_MODELS.contains(&model);
+                if !is_local {
+                    warn!(
+                        "Excluding device '{}' with model '{model}', because it isn't a known Alibaba local NVMe disk.",
+                        &device.path
+                    );
+                }
+                is_local
+            })
+            .paths()
+            .collect())
+    }
+
+    fn detect_openstack_devices(&self) -> Result<ProviderDevices, Error> {
+        // Nova ephemeral disks are usually formatted and mounted at /mnt
+        // by cloud-init, so mounts and children don't rule them out here.
+        // Instead, we look for the filesystem label Nova gives them,
+        // and fall back to the config drive's block device mapping
+        // when they have been reformatted without it.
+        let host_disks = self.host_disks();
+        let devices = self.lsblk_all()?;
+        let candidates: Vec<&LsblkBlockDevice> = devices
+            .iter()
+            .filter(|device| {
+                let exclusion = host_disks
+                    .exclusion(&device.path)
+                    .filter(|exclusion| exclusion.reason != ExclusionReason::Mounted)
+                    .or_else(|| self.attribute_exclusion(device));
+                match exclusion {
+                    Some(exclusion) => {
+                        debug!(
+                            "Excluding device '{}' because {}.",
+                            &device.path, exclusion.detail
+                        );
+                        false
+                    }
+                    None => true,
+                }
+            })
+            .collect();
+        let mut ephemeral: Vec<&LsblkBlockDevice> = candidates
+            .iter()
+            .copied()
+            .filter(|device| {
+                device.label.as_deref() == Some(OPENSTACK_EPHEMERAL_LABEL)
+                    || device
+                        .children
+                        .iter()
+                        .flatten()
+                        .any(|child| child.label.as_deref() == Some(OPENSTACK_EPHEMERAL_LABEL))
+            })
+            .collect();
+        if ephemeral.is_empty() {
+            debug!("No device labeled '{OPENSTACK_EPHEMERAL_LABEL}', checking the config drive");
+            let names = self.openstack_config_drive_ephemeral_names(&devices)?;
+            ephemeral = candidates
+                .iter()
+                .copied()
+                .filter(|device| {
+                    names
+                        .iter()
+                        .any(|name| device.path == format!("/dev/{name}"))
+                })
+                .collect();
+        }
+
+        let mut provider_devices = ProviderDevices::default();
+        for device in ephemeral {
+            if let Some(exclusion) = self.exclusion(device, &host_disks) {
+                if !self.options.openstack_reclaim_ephemeral {
+                    warn!(
+                        "Excluding ephemeral disk '{}' because {}. Pass --openstack-reclaim-ephemeral to unmount and use it.",
+                        &device.path, exclusion.detail
+                    );
+                    continue;
+                }
+                info!(
+                    "Reclaiming ephemeral disk '{}' before using it, because {}",
+                    &device.path, exclusion.detail
+                );
+                provider_devices.reclaim.push(device.path.clone());
+            }
+            provider_devices.paths.push(device.path.clone());
+        }
+        Ok(provider_devices)
+    }
+
+    /// Returns the kernel names of the ephemeral disks listed in the
+    /// config drive's block device mapping.
+    fn openstack_config_drive_ephemeral_names(
+        &self,
+        devices: &[LsblkBlockDevice],
+    ) -> Result<Vec<String>, Error> {
+        let Some(config_drive) = devices.iter().find(|device| {
+            device
+                .label
+                .as_deref()
+                .is_some_and(|label| label.eq_ignore_ascii_case(OPENSTACK_CONFIG_DRIVE_LABEL))
+        }) else {
+            debug!("No config drive found");
+            return Ok(vec![]);
+        };
+        if let Some(mountpoint) = config_drive.mountpoints.first() {
+            return read_config_drive_ephemeral_names(Path::new(mountpoint));
+        }
+
+        // The directory is removed when we're done with it, mounted or not.
+        let mountpoint = tempfile::Builder::new()
+            .prefix("ephemeral-storage-setup-config-drive")
+            .tempdir()
+            .map_err(Error::io(std::env::temp_dir()))?;
+        // Commands take UTF-8 arguments, and TMPDIR might not be.
+        let mountpoint_str = mountpoint.path().to_str().ok_or_else(|| {
+            Error::io(mountpoint.path())(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "temporary directory path is not UTF-8",
+            ))
+        })?;
+        let device = self.options.host_root.device(&config_drive.path);
+        self.commander
+            .check_output(&["mount", "-o", "ro", &device, mountpoint_str])?;
+        let names = read_config_drive_ephemeral_names(mountpoint.path());
+        self.commander.check_output(&["umount", mountpoint_str])?;
+        names
+    }
+
+    /// Unmounts a device and its children, both in our mount namespace and
+    /// in the host's, then, if nothing else is using it,
+    /// wipes their signatures so the device can be reused.
+    fn reclaim_device(&self, device: &LsblkBlockDevice) -> Result<(), Error> {
+        info!("Reclaiming device '{}'", &device.path);
+        let children: Vec<&LsblkBlockDevice> = device.children.iter().flatten().collect();
+        for dev in children.iter().copied().chain([device]) {
+            for mountpoint in &dev.mountpoints {
+                info!("Unmounting '{}' from '{mountpoint}'", &dev.path);
+                self.commander.check_output(&["umount", mountpoint])?;
+            }
+            // The host may have it mounted even when we don't see it,
+            // so always try there too. This requires the host PID namespace.
+            let output = self.commander.unchecked_output(&[
+                "nsenter",
+                "--target",
+                "1",
+                "--mount",
+                "--",
+                "umount",
+                "--all-targets",
+                &dev.path,
+            ])?;
+            if output.status.success() {
+                info!("Unmounted '{}' on the host", &dev.path);
+            }
+        }
+        let host_root = &self.options.host_root;
+        check_not_in_use(host_root, &[host_root.device(&device.path)])?;
+        for dev in children.iter().copied().chain([device]) {
+            self.commander
+                .check_output(&["wipefs", "--all", &host_root.device(&dev.path)])?;
+        }
+        if !children.is_empty() {
+            self.commander.check_output(&[
+                "blockdev",
+                "--rereadpt",
+                &host_root.device(&device.path),
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn detect_generic_devices(&self) -> Result<Vec<String>, Error> {
+        Ok(self.lsblk()?.paths().collect())
+    }
+}
+
+#[derive(Deserialize)]
+struct Ec2MetaData {
+    #[serde(rename = "block-device-mapping", default)]
+    block_device_mapping: HashMap<String, String>,
+}
+
+/// Reads the ephemeral entries of the block device mapping from a mounted
+/// config drive, returning kernel device names like `vdb`.
+fn read_config_drive_ephemeral_names(config_drive: &Path) -> Result<Vec<String>, Error> {
+    let path: PathBuf = config_drive.join("ec2/latest/meta-data.json");
+    let meta_data = match std::fs::read(&path) {
+        Ok(meta_data) => meta_data,
+        Err(e) => {
+            warn!(
+                "Failed to read config drive metadata '{}': {e}",
+                path.display()
+            );
+            return Ok(vec![]);
+        }
+    };
+    let meta_data: Ec2MetaData = serde_json::from_slice(&meta_data)
+        .map_err(Error::parse(format!("'{}'", path.display())))?;
+    let mut names: Vec<String> = meta_data
+        .block_device_mapping
+        .into_iter()
+        .filter(|(name, _)| name.starts_with("ephemeral"))
+        .map(|(_, device)| device.trim_start_matches("/dev/").to_owned())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Returns the value of a key in a waagent.conf style file,
+/// which has one `Key=value` per line and `#` comments.
+fn waagent_conf_value<'a>(conf: &'a str, key: &str) -> Option<&'a str> {
+    conf.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, value)| value.trim())
+}
+
+/// Matches a path against a shell style glob, where `*` matches any run
+/// of characters and `?` matches any one character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Where to resume after the last `*`, if the rest doesn't match.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Alibaba cloud disk IDs look like `d-bp1f2nmt5wfzx1l4x3kq`: "d-",
+/// then a three character region prefix with a letter and a digit,
+/// like `bp1` in Hangzhou or `2ze` in Beijing, then 17 random lowercase
+/// alphanumerics. NVMe cloud disks report it as their serial,
+/// usually without the "d-".
+fn is_alibaba_cloud_disk_serial(serial: &str) -> bool {
+    static DISK_ID: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new("^(?:d-)?([0-9a-z]{3})[0-9a-z]{17}$").unwrap());
+    DISK_ID.captures(serial.trim()).is_some_and(|captures| {
+        let region = &captures[1];
+        region.chars().any(|c| c.is_ascii_lowercase()) && region.chars().any(|c| c.is_ascii_digit())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::CloudProvider;
+    use crate::catalog::{CatalogMismatch, CatalogPolicy, DiskCount};
+    use crate::detect::{
+        BlockDeviceSource, DetectOptions, DiskDetector, DiskDetectorTrait, DiskGroup,
+        DiskGroupPolicy, DiskInfo, HealthPolicy, LsblkBlockDevice, NamespacePolicy, NvmeController,
+        ProviderDevices, glob_match, is_alibaba_cloud_disk_serial,
+        read_config_drive_ephemeral_names, waagent_conf_value,
+    };
+    use crate::error::Error;
+    use crate::host_root::HostRoot;
+    use crate::metadata::InstanceMetadata;
+    use crate::nvme::{IdentifyController, NvmeAdmin, SmartLog};
+    use crate::test::TestEnv;
+
+    /// Reads captured admin command data for controllers,
+    /// as we can't issue ioctls in tests.
+    struct FixtureNvmeAdmin;
+
+    impl NvmeAdmin for FixtureNvmeAdmin {
+        fn identify_controller(&self, controller: &Path) -> io::Result<IdentifyController> {
+            IdentifyController::parse(&std::fs::read(nvme_fixture("aws", controller))?)
+        }
+
+        fn smart_log(&self, controller: &Path) -> io::Result<SmartLog> {
+            SmartLog::parse(&std::fs::read(nvme_fixture("smart", controller))?)
+        }
+    }
+
+    fn nvme_fixture(dir: &str, controller: &Path) -> PathBuf {
+        let name = controller.file_name().unwrap().to_str().unwrap();
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("testdata/nvme/{dir}/{name}.bin"))
+    }
+
+    /// Options that read the host's state from an empty host root,
+    /// as the machine running the tests has nothing to do with our test data.
+    fn test_options(test_env: &TestEnv) -> DetectOptions {
+        DetectOptions {
+            host_root: test_env.host_root(),
+            ..Default::default()
+        }
+    }
+
+    /// Makes links in a host directory like `/dev/disk/by-id`, like udev
+    /// would, to devices by name, and returns the host root they're under.
+    fn udev_links(test_env: &TestEnv, dir: &str, links: &[(&str, &str)]) -> HostRoot {
+        let host_root = test_env.host_root();
+        let dir = host_root.path(dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (link, device) in links {
+            std::os::unix::fs::symlink(format!("../../{device}"), dir.join(link)).unwrap();
+        }
+        test_env.host_root()
+    }
+
+    fn test_detector(
+        test_env: &TestEnv,
+        cloud_provider: CloudProvider,
+        options: DetectOptions,
+    ) -> DiskDetector {
+        // Host roots of sysfs fixtures have our mount table too.
+        let mountinfo = options.host_root.path("/proc/self/mountinfo");
+        DiskDetector {
+            cloud_provider,
+            commander: test_env.commander.clone(),
+            options,
+            nvme_admin: Arc::new(FixtureNvmeAdmin),
+            mountinfo,
+        }
+    }
+
+    #[test]
+    fn test_lsblk_filters() {
+        let test_env = TestEnv::new();
+        let disk_detector = test_detector(&test_env, CloudProvider::Aws, test_options(&test_env));
 
         let lsblk_output = test_env.read_testdata("testdata/lsblk_contrived.json");
         test_env.mock("lsblk", 0, &lsblk_output);
         let expected: Vec<LsblkBlockDevice> = vec![
             LsblkBlockDevice {
+                label: None,
                 children: Some(vec![]),
                 model: Some("Amazon EC2 NVMe Instance Storage".to_owned()),
-                mountpoint: None,
+                mountpoints: vec![],
+                serial: None,
                 path: "/dev/nvme0n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
+                vendor: None,
+                wwn: None,
+                size: None,
+                rota: None,
             },
             LsblkBlockDevice {
+                label: None,
                 children: Some(vec![]),
                 model: Some("Amazon EC2 NVMe Instance Storage".to_owned()),
-                mountpoint: None,
+                mountpoints: vec![],
+                serial: None,
                 path: "/dev/nvme1n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
+                vendor: None,
+                wwn: None,
+                size: None,
+                rota: None,
             },
             LsblkBlockDevice {
+                label: None,
                 children: Some(vec![]),
                 model: Some("some other model".to_owned()),
-                mountpoint: None,
+                mountpoints: vec![],
+                serial: None,
                 path: "/dev/nvme2n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
+                vendor: None,
+                wwn: None,
+                size: None,
+                rota: None,
             },
             LsblkBlockDevice {
+                label: None,
                 children: None,
                 model: Some("Amazon EC2 NVMe Instance Storage".to_owned()),
-                mountpoint: None,
+                mountpoints: vec![],
+                serial: None,
                 path: "/dev/nvme7n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
+                vendor: None,
+                wwn: None,
+                size: None,
+                rota: None,
             },
             LsblkBlockDevice {
+                label: None,
                 children: None,
                 model: Some("Microsoft NVMe Direct Disk v49990322".to_owned()),
-                mountpoint: None,
+                mountpoints: vec![],
+                serial: None,
                 path: "/dev/nvme8n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
+                vendor: None,
+                wwn: None,
+                size: None,
+                rota: None,
             },
             LsblkBlockDevice {
+                label: None,
                 children: None,
                 model: Some("nvme_card".to_owned()),
-                mountpoint: None,
+                mountpoints: vec![],
+                serial: None,
                 path: "/dev/nvme9n1".to_owned(),
                 tran: Some("nvme".to_owned()),
                 type_: "disk".to_owned(),
+                vendor: None,
+                wwn: None,
+                size: None,
+                rota: None,
+            },
+        ];
+        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().unwrap().collect();
+        assert_eq!(expected, actual);
+
+        let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let expected: Vec<LsblkBlockDevice> = vec![LsblkBlockDevice {
+            label: None,
+            children: None,
+            model: Some("Amazon EC2 NVMe Instance Storage        ".to_owned()),
+            mountpoints: vec![],
+            serial: Some("AWS3CEF3078A8D77867C".to_owned()),
+            path: "/dev/nvme1n1".to_owned(),
+            tran: Some("nvme".to_owned()),
+            type_: "disk".to_owned(),
+            vendor: None,
+            wwn: None,
+            // 441.4G
+            size: Some(473949641113),
+            rota: Some(false),
+        }];
+        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().unwrap().collect();
+        assert_eq!(expected, actual);
+
+        let lsblk_output = test_env.read_testdata("testdata/azure/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let expected: Vec<LsblkBlockDevice> = vec![LsblkBlockDevice {
+            label: None,
+            children: None,
+            model: Some("Microsoft NVMe Direct Disk v2           ".to_owned()),
+            mountpoints: vec![],
+            serial: Some("951611405036e7560001".to_owned()),
+            path: "/dev/nvme0n1".to_owned(),
+            tran: Some("nvme".to_owned()),
+            type_: "disk".to_owned(),
+            vendor: None,
+            wwn: None,
+            size: Some(220 << 30),
+            rota: Some(false),
+        }];
+        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().unwrap().collect();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_detect_generic_devices_with_selector() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/oci/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Generic,
+            DetectOptions {
+                selector: Some(r#"model=~"SAMSUNG.*" && size>=1TiB"#.parse().unwrap()),
+                ..test_options(&test_env)
+            },
+        );
+        let expected = vec!["/dev/nvme0n1".to_owned(), "/dev/nvme1n1".to_owned()];
+        let actual = disk_detector.detect_generic_devices().unwrap();
+        assert_eq!(expected, actual);
+
+        // The selector replaces the NVMe transport check for the generic provider.
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Generic,
+            DetectOptions {
+                selector: Some("tran in (iscsi)".parse().unwrap()),
+                ..test_options(&test_env)
+            },
+        );
+        let expected = vec!["/dev/sdb".to_owned()];
+        let actual = disk_detector.detect_generic_devices().unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_lsblk_and_sysfs_agree() {
+        let test_env = TestEnv::new();
+        for (cloud_provider, name) in [(CloudProvider::Aws, "aws"), (CloudProvider::Azure, "azure")]
+        {
+            let lsblk_output = test_env.read_testdata(&format!("testdata/{name}/lsblk.json"));
+            test_env.mock("lsblk", 0, &lsblk_output);
+            let lsblk_detector = test_detector(&test_env, cloud_provider, test_options(&test_env));
+            let sysfs_detector = test_detector(
+                &test_env,
+                cloud_provider,
+                DetectOptions {
+                    block_device_source: BlockDeviceSource::Sysfs,
+                    host_root: HostRoot::new(
+                        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                            .join(format!("testdata/sysfs/{name}")),
+                    ),
+                    ..Default::default()
+                },
+            );
+
+            let summarize = |devices: Vec<LsblkBlockDevice>| -> Vec<_> {
+                devices
+                    .into_iter()
+                    .map(|device| {
+                        (
+                            device.path,
+                            device.type_,
+                            device.tran,
+                            device.model,
+                            device.children.unwrap_or_default().len(),
+                            !device.mountpoints.is_empty(),
+                        )
+                    })
+                    .collect()
+            };
+            assert_eq!(
+                summarize(lsblk_detector.lsblk_all().unwrap()),
+                summarize(sysfs_detector.lsblk_all().unwrap()),
+                "{name}"
+            );
+            assert_eq!(
+                summarize(lsblk_detector.lsblk().unwrap().collect()),
+                summarize(sysfs_detector.lsblk().unwrap().collect()),
+                "{name}"
+            );
+            assert_eq!(
+                lsblk_detector.detect_devices().unwrap(),
+                sysfs_detector.detect_devices().unwrap(),
+                "{name}"
+            );
+        }
+
+        // We fall back to lsblk when sysfs isn't there.
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Azure,
+            DetectOptions {
+                block_device_source: BlockDeviceSource::Sysfs,
+                host_root: HostRoot::new("/nonexistent"),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            disk_detector.detect_devices().unwrap(),
+            vec!["/dev/nvme0n1"]
+        );
+    }
+
+    #[test]
+    fn test_detect_disks() {
+        let test_env = TestEnv::new();
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/sysfs/aws");
+        let mut disk_detector = DiskDetector::builder(CloudProvider::Aws)
+            .commander(test_env.commander.clone())
+            .block_device_source(BlockDeviceSource::Sysfs)
+            .host_root(HostRoot::new(&root))
+            .min_disks(1)
+            .build()
+            .unwrap();
+        // Mountpoints come from our own mount table, not the host root's.
+        assert_eq!(disk_detector.mountinfo, Path::new("/proc/self/mountinfo"));
+        disk_detector.nvme_admin = Arc::new(FixtureNvmeAdmin);
+        disk_detector.mountinfo = root.join("proc/self/mountinfo");
+        assert_eq!(
+            disk_detector.detect_disks().unwrap(),
+            vec![DiskInfo {
+                path: "/dev/nvme1n1".to_owned(),
+                by_id: None,
+                size: Some(473949640704),
+                model: Some("Amazon EC2 NVMe Instance Storage".to_owned()),
+                serial: Some("AWS3CEF3078A8D77867C".to_owned()),
+                firmware: None,
+                wwn: Some("nvme.1d0f-4157533343454633303738413844373738363743-416d617a6f6e20454332204e564d6520496e7374616e63652053746f72616765-00000001".to_owned()),
+                transport: Some("nvme".to_owned()),
+                numa_node: Some(0),
+                reclaim: false,
+            }]
+        );
+
+        let result = DiskDetector::builder(CloudProvider::Auto).build();
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+        // Without the builder's check, detecting fails rather than panics.
+        let disk_detector = DiskDetector::with_options(
+            test_env.commander.clone(),
+            CloudProvider::Auto,
+            test_options(&test_env),
+        );
+        let result = disk_detector.detect_disks();
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_detect_devices_wait() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let detector = |expected_disks, min_disks, device_wait_timeout| {
+            test_detector(
+                &test_env,
+                CloudProvider::Aws,
+                DetectOptions {
+                    expected_disks,
+                    min_disks,
+                    device_wait_timeout,
+                    ..test_options(&test_env)
+                },
+            )
+        };
+
+        // We continue with fewer than expected, as long as we have the minimum.
+        let actual = detector(Some(2), None, Duration::ZERO)
+            .detect_devices()
+            .unwrap();
+        assert_eq!(actual, vec!["/dev/nvme1n1".to_owned()]);
+
+        let actual = detector(Some(2), Some(2), Duration::ZERO).detect_devices();
+        assert!(matches!(
+            actual,
+            Err(Error::NotEnoughDevices { found, min_disks: 2, .. }) if found == vec!["/dev/nvme1n1"]
+        ));
+
+        // The second device shows up on the next poll.
+        let mut lsblk_output: serde_json::Value = serde_json::from_str(&lsblk_output).unwrap();
+        let first_poll = lsblk_output.to_string();
+        let mut second = lsblk_output["blockdevices"][1].clone();
+        second["name"] = "nvme2n1".into();
+        second["path"] = "/dev/nvme2n1".into();
+        lsblk_output["blockdevices"]
+            .as_array_mut()
+            .unwrap()
+            .push(second);
+        let polled = test_env.temp_dir.path().join("lsblk-polled");
+        test_env.mock_script(
+            "lsblk",
+            &format!(
+                "if [[ -e '{polled}' ]]; then
+    echo '{lsblk_output}'
+else
+    touch '{polled}'
+    echo '{first_poll}'
+fi
+",
+                polled = polled.display(),
+            ),
+        );
+        let actual = detector(Some(2), Some(2), Duration::from_secs(10))
+            .detect_devices()
+            .unwrap();
+        assert_eq!(
+            actual,
+            vec!["/dev/nvme1n1".to_owned(), "/dev/nvme2n1".to_owned()]
+        );
+
+        let lsblk_output = test_env.read_testdata("testdata/virtio/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let actual = detector(None, None, Duration::ZERO).detect_devices();
+        assert!(matches!(actual, Err(Error::NoDevices { .. })));
+    }
+
+    #[test]
+    fn test_detect_devices_catalog() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let detector = |instance_type: &str, catalog_policy| {
+            test_detector(
+                &test_env,
+                CloudProvider::Aws,
+                DetectOptions {
+                    instance_metadata: Some(InstanceMetadata {
+                        instance_type: instance_type.to_owned(),
+                        ..Default::default()
+                    }),
+                    catalog_policy,
+                    ..test_options(&test_env)
+                },
+            )
+        };
+
+        let expected = vec!["/dev/nvme1n1".to_owned()];
+        let actual = detector("r6gd.2xlarge", CatalogPolicy::Fail).detect_devices();
+        assert_eq!(expected, actual.unwrap());
+        let actual = detector("i4i.8xlarge", CatalogPolicy::Warn).detect_devices();
+        assert_eq!(expected, actual.unwrap());
+        let actual = detector("i4i.8xlarge", CatalogPolicy::Ignore).detect_devices();
+        assert_eq!(expected, actual.unwrap());
+        // Unknown instance types aren't checked.
+        let actual = detector("x9.huge", CatalogPolicy::Fail).detect_devices();
+        assert_eq!(expected, actual.unwrap());
+
+        let actual = detector("i4i.8xlarge", CatalogPolicy::Fail).detect_devices();
+        let Err(Error::CatalogMismatch(mismatches)) = actual else {
+            panic!("expected a catalog mismatch, got {actual:?}");
+        };
+        assert_eq!(
+            mismatches,
+            vec![
+                CatalogMismatch::DiskCount {
+                    instance_type: "i4i.8xlarge".to_owned(),
+                    expected: DiskCount::Exactly(2),
+                    found: 1,
+                },
+                CatalogMismatch::DiskSize {
+                    instance_type: "i4i.8xlarge".to_owned(),
+                    device: "/dev/nvme1n1".to_owned(),
+                    expected: 3_750_000_000_000,
+                    found: Some(473949641113),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disk_group_policy() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/lsblk_mixed.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let detector = |disk_group_policy| {
+            test_detector(
+                &test_env,
+                CloudProvider::Generic,
+                DetectOptions {
+                    disk_group_policy,
+                    ..test_options(&test_env)
+                },
+            )
+        };
+        let group = |model: &str, size: u64, disks: &[(&str, &str)]| DiskGroup {
+            model: Some(model.to_owned()),
+            size: Some(size),
+            transport: Some("nvme".to_owned()),
+            devices: disks
+                .iter()
+                .map(|(path, serial)| DiskInfo {
+                    path: (*path).to_owned(),
+                    size: Some(size),
+                    model: Some(model.to_owned()),
+                    serial: Some((*serial).to_owned()),
+                    transport: Some("nvme".to_owned()),
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let micron = group(
+            "Micron_7450_MTFDKCC1T9TFR",
+            1920383410176,
+            &[
+                ("/dev/nvme0n1", "MICRON0000"),
+                ("/dev/nvme3n1", "MICRON0003"),
+            ],
+        );
+        let samsung = group(
+            "SAMSUNG MZQL23T8HCLS-00A07",
+            3840755982336,
+            &[
+                ("/dev/nvme1n1", "SAMSUNG0001"),
+                ("/dev/nvme2n1", "SAMSUNG0002"),
+                ("/dev/nvme4n1", "SAMSUNG0004"),
+            ],
+        );
+
+        let actual = detector(DiskGroupPolicy::All)
+            .detect_device_groups()
+            .unwrap();
+        assert_eq!(actual, vec![micron.clone(), samsung.clone()]);
+
+        let actual = detector(DiskGroupPolicy::Largest)
+            .detect_device_groups()
+            .unwrap();
+        assert_eq!(actual, vec![samsung.clone()]);
+
+        let actual = detector(DiskGroupPolicy::Fail).detect_device_groups();
+        let Err(Error::HeterogeneousDisks(groups)) = actual else {
+            panic!("expected heterogeneous disks, got {actual:?}");
+        };
+        assert_eq!(groups, vec![micron, samsung]);
+
+        // With a single group, there is nothing to choose.
+        let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let actual = test_detector(
+            &test_env,
+            CloudProvider::Aws,
+            DetectOptions {
+                disk_group_policy: DiskGroupPolicy::Fail,
+                ..test_options(&test_env)
+            },
+        )
+        .detect_devices()
+        .unwrap();
+        assert_eq!(actual, vec!["/dev/nvme1n1".to_owned()]);
+    }
+
+    #[test]
+    fn test_include_exclude_devices() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/lsblk_mixed.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let host_root = udev_links(
+            &test_env,
+            "/dev/disk/by-id",
+            &[
+                ("nvme-SAMSUNG_MZQL23T8HCLS-00A07_SAMSUNG0001", "nvme1n1"),
+                (
+                    "nvme-SAMSUNG_MZQL23T8HCLS-00A07_SAMSUNG0001-part1",
+                    "nvme1n1p1",
+                ),
+                ("nvme-SAMSUNG_MZQL23T8HCLS-00A07_SAMSUNG0002", "nvme2n1"),
+            ],
+        );
+        udev_links(
+            &test_env,
+            "/dev/disk/by-path",
+            &[
+                ("pci-0000:00:04.0-nvme-1", "nvme3n1"),
+                ("pci-0000:00:04.0-nvme-1-part1", "nvme3n1p1"),
+            ],
+        );
+        let device = |path: &str| host_root.device(path);
+        let detector = |include_devices: &[&str], exclude_devices: &[&str]| {
+            test_detector(
+                &test_env,
+                CloudProvider::Generic,
+                DetectOptions {
+                    include_devices: include_devices.iter().map(|d| d.to_string()).collect(),
+                    exclude_devices: exclude_devices.iter().map(|d| d.to_string()).collect(),
+                    host_root: host_root.clone(),
+                    ..Default::default()
+                },
+            )
+        };
+
+        let actual = detector(&[], &["/dev/nvme0n1", "/dev/nvme4*"])
+            .detect_devices()
+            .unwrap();
+        assert_eq!(
+            actual,
+            vec![
+                device("/dev/nvme1n1"),
+                device("/dev/nvme2n1"),
+                device("/dev/nvme3n1")
+            ]
+        );
+
+        let actual = detector(&["/dev/disk/by-id/nvme-SAMSUNG*"], &[])
+            .detect_devices()
+            .unwrap();
+        assert_eq!(actual, vec![device("/dev/nvme1n1"), device("/dev/nvme2n1")]);
+
+        // Excludes win over includes.
+        let actual = detector(&["/dev/disk/by-id/nvme-SAMSUNG*"], &["/dev/nvme2n1"])
+            .detect_devices()
+            .unwrap();
+        assert_eq!(actual, vec![device("/dev/nvme1n1")]);
+
+        // Globs can pick the directory of links too.
+        let actual = detector(&["/dev/disk/by-*/pci-*-nvme-1"], &[])
+            .detect_devices()
+            .unwrap();
+        assert_eq!(actual, vec![device("/dev/nvme3n1")]);
+        let actual = detector(&["/dev/disk/by-*/*"], &["/dev/disk/by-path/*"])
+            .detect_devices()
+            .unwrap();
+        assert_eq!(actual, vec![device("/dev/nvme1n1"), device("/dev/nvme2n1")]);
+
+        // Includes only narrow down what the provider detected.
+        let actual = detector(&["/dev/nvme9n1"], &[]).detect_devices();
+        assert!(matches!(actual, Err(Error::NoDevices { .. })));
+    }
+
+    #[test]
+    fn test_inventory() {
+        let test_env = TestEnv::new();
+        let reasons = |disk_detector: DiskDetector| -> Vec<(String, bool, serde_json::Value)> {
+            let inventory = serde_json::to_value(disk_detector.inventory().unwrap()).unwrap();
+            inventory["devices"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|device| {
+                    (
+                        device["path"].as_str().unwrap().to_owned(),
+                        device["selected"].as_bool().unwrap(),
+                        device["reason"].clone(),
+                    )
+                })
+                .collect()
+        };
+
+        let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let disk_detector = test_detector(&test_env, CloudProvider::Aws, test_options(&test_env));
+        let inventory = serde_json::to_value(disk_detector.inventory().unwrap()).unwrap();
+        assert_eq!(inventory["cloud_provider"], "aws");
+        assert_eq!(
+            inventory["devices"][3]["attributes"]["size"],
+            473949641113u64
+        );
+        assert_eq!(inventory["devices"][3]["attributes"]["type"], "disk");
+        assert_eq!(
+            reasons(disk_detector),
+            vec![
+                ("/dev/nvme0n1".to_owned(), false, "has_children".into()),
+                ("/dev/nvme0n1p1".to_owned(), false, "mounted".into()),
+                ("/dev/nvme0n1p128".to_owned(), false, "not_disk".into()),
+                ("/dev/nvme1n1".to_owned(), true, serde_json::Value::Null),
+            ]
+        );
+
+        let lsblk_output = test_env.read_testdata("testdata/lsblk_mixed.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Generic,
+            DetectOptions {
+                disk_group_policy: DiskGroupPolicy::Largest,
+                exclude_devices: vec!["/dev/nvme4n1".to_owned()],
+                ..test_options(&test_env)
             },
+        );
+        assert_eq!(
+            reasons(disk_detector),
+            vec![
+                ("/dev/nvme0n1".to_owned(), false, "disk_group".into()),
+                ("/dev/nvme1n1".to_owned(), true, serde_json::Value::Null),
+                ("/dev/nvme2n1".to_owned(), true, serde_json::Value::Null),
+                ("/dev/nvme3n1".to_owned(), false, "disk_group".into()),
+                ("/dev/nvme4n1".to_owned(), false, "exclude_device".into()),
+            ]
+        );
+
+        let disk_detector = test_detector(&test_env, CloudProvider::Azure, test_options(&test_env));
+        assert!(
+            reasons(disk_detector)
+                .iter()
+                .all(|(_, selected, reason)| !selected && reason == "provider")
+        );
+    }
+
+    #[test]
+    fn test_health_policy() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/lsblk_health.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let detector = |health_policy| {
+            test_detector(
+                &test_env,
+                CloudProvider::Generic,
+                DetectOptions {
+                    health_policy,
+                    ..test_options(&test_env)
+                },
+            )
+        };
+        let all = vec![
+            "/dev/nvme10n1".to_owned(),
+            "/dev/nvme11n1".to_owned(),
+            "/dev/nvme12n1".to_owned(),
+            "/dev/nvme13n1".to_owned(),
         ];
-        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().collect();
-        assert_eq!(expected, actual);
 
-        let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
+        // Only nvme10 is healthy. nvme11 is overheating, nvme12 is worn out,
+        // and nvme13 has media errors.
+        let actual = detector(HealthPolicy::Exclude).detect_devices().unwrap();
+        assert_eq!(actual, vec!["/dev/nvme10n1".to_owned()]);
+
+        let actual = detector(HealthPolicy::Warn).detect_devices().unwrap();
+        assert_eq!(actual, all);
+        // Unhealthy disks are only dropped when asked to.
+        let actual = detector(HealthPolicy::default()).detect_devices().unwrap();
+        assert_eq!(actual, all);
+        let actual = detector(HealthPolicy::Ignore).detect_devices().unwrap();
+        assert_eq!(actual, all);
+
+        let actual = detector(HealthPolicy::Fail).detect_devices();
+        let Err(Error::UnhealthyDisks(disks)) = actual else {
+            panic!("expected unhealthy disks, got {actual:?}");
+        };
+        assert_eq!(
+            disks.iter().map(|disk| &disk.device).collect::<Vec<_>>(),
+            vec!["/dev/nvme11n1", "/dev/nvme12n1", "/dev/nvme13n1"]
+        );
+        assert_eq!(disks[2].problems, vec!["27 media errors"]);
+
+        let inventory =
+            serde_json::to_value(detector(HealthPolicy::Exclude).inventory().unwrap()).unwrap();
+        assert_eq!(inventory["devices"][0]["selected"], true);
+        assert_eq!(inventory["devices"][1]["reason"], "unhealthy");
+        assert_eq!(
+            inventory["devices"][1]["detail"],
+            "critical warning: temperature is outside its thresholds"
+        );
+
+        // Devices that aren't NVMe namespaces don't have a log to read.
+        let mut lsblk: serde_json::Value = serde_json::from_str(&lsblk_output).unwrap();
+        lsblk["blockdevices"][1]["path"] = "/dev/sdb".into();
+        test_env.mock("lsblk", 0, &lsblk.to_string());
+        let actual = detector(HealthPolicy::Exclude).detect_devices().unwrap();
+        assert_eq!(
+            actual,
+            vec!["/dev/nvme10n1".to_owned(), "/dev/sdb".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_namespace_policy() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/gcp/lsblk_namespaces.json");
         test_env.mock("lsblk", 0, &lsblk_output);
-        let expected: Vec<LsblkBlockDevice> = vec![LsblkBlockDevice {
-            children: None,
-            model: Some("Amazon EC2 NVMe Instance Storage        ".to_owned()),
-            mountpoint: None,
-            path: "/dev/nvme1n1".to_owned(),
-            tran: Some("nvme".to_owned()),
-            type_: "disk".to_owned(),
-        }];
-        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().collect();
-        assert_eq!(expected, actual);
+        // One controller with four local SSD namespaces.
+        let host_root = udev_links(
+            &test_env,
+            "/dev/disk/by-id",
+            &[
+                ("google-local-nvme-ssd-0", "nvme0n1"),
+                ("google-local-nvme-ssd-1", "nvme0n2"),
+                ("google-local-nvme-ssd-2", "nvme0n3"),
+                ("google-local-nvme-ssd-3", "nvme0n4"),
+            ],
+        );
+        let detector = |namespace_policy, namespaces_per_controller| {
+            test_detector(
+                &test_env,
+                CloudProvider::Gcp,
+                DetectOptions {
+                    namespace_policy,
+                    namespaces_per_controller,
+                    host_root: host_root.clone(),
+                    ..Default::default()
+                },
+            )
+        };
+        let all = vec![
+            "/dev/nvme0n1".to_owned(),
+            "/dev/nvme0n2".to_owned(),
+            "/dev/nvme0n3".to_owned(),
+            "/dev/nvme0n4".to_owned(),
+        ];
+        let devices = |paths: &[String]| -> Vec<String> {
+            paths.iter().map(|path| host_root.device(path)).collect()
+        };
 
-        let lsblk_output = test_env.read_testdata("testdata/azure/lsblk.json");
+        let actual = detector(NamespacePolicy::All, None)
+            .detect_devices()
+            .unwrap();
+        assert_eq!(actual, devices(&all));
+
+        let actual = detector(NamespacePolicy::First, None)
+            .detect_devices()
+            .unwrap();
+        assert_eq!(actual, devices(&all[..1]));
+
+        let actual = detector(NamespacePolicy::Require, Some(4))
+            .detect_devices()
+            .unwrap();
+        assert_eq!(actual, devices(&all));
+
+        let actual = detector(NamespacePolicy::Require, Some(2)).detect_devices();
+        let Err(Error::NamespaceLayout {
+            expected: 2,
+            controllers,
+        }) = actual
+        else {
+            panic!("expected a namespace layout error, got {actual:?}");
+        };
+        assert_eq!(
+            controllers,
+            vec![NvmeController {
+                controller: "/dev/nvme0".to_owned(),
+                namespaces: all.clone(),
+            }]
+        );
+
+        let inventory =
+            serde_json::to_value(detector(NamespacePolicy::First, None).inventory().unwrap())
+                .unwrap();
+        let device = |path: &str| {
+            inventory["devices"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|device| device["path"] == path)
+                .unwrap()
+                .clone()
+        };
+        let nvme0n2 = device("/dev/nvme0n2");
+        assert_eq!(nvme0n2["controller"], "/dev/nvme0");
+        assert_eq!(nvme0n2["namespace"], 2);
+        assert_eq!(nvme0n2["reason"], "namespace");
+        assert_eq!(device("/dev/nvme0n1")["selected"], true);
+        assert_eq!(device("/dev/sda")["controller"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_lsblk_schemas() {
+        let test_env = TestEnv::new();
+        let disk_detector = test_detector(&test_env, CloudProvider::Aws, test_options(&test_env));
+        // The same node, as lsblk from different versions of util-linux reports it.
+        // 2.32 has no path column and reports everything as strings,
+        // 2.34 has a single mountpoint, and 2.37 adds mountpoints,
+        // but doesn't report the transport of partitions.
+        for version in ["2.32", "2.34", "2.37"] {
+            let lsblk_output =
+                test_env.read_testdata(&format!("testdata/lsblk/util-linux-{version}.json"));
+            test_env.mock("lsblk", 0, &lsblk_output);
+            let devices = disk_detector.lsblk_all().unwrap();
+            let root_partition = &devices[0].children.as_ref().unwrap()[0];
+            assert_eq!(root_partition.path, "/dev/nvme0n1p1", "{version}");
+            assert_eq!(root_partition.tran.as_deref(), Some("nvme"), "{version}");
+            assert_eq!(root_partition.mountpoints[0], "/", "{version}");
+            assert_eq!(devices[1].size, Some(474000000000), "{version}");
+            assert_eq!(devices[1].rota, Some(false), "{version}");
+            assert_eq!(devices[2].mountpoints, vec!["/mnt/scratch"], "{version}");
+
+            let actual = disk_detector.detect_devices().unwrap();
+            assert_eq!(actual, vec!["/dev/nvme1n1".to_owned()], "{version}");
+        }
+
+        // Only 2.37 and later see more than one mountpoint.
+        let devices = disk_detector.lsblk_all().unwrap();
+        assert_eq!(
+            devices[0].children.as_ref().unwrap()[0].mountpoints,
+            vec!["/", "/var/lib/kubelet"]
+        );
+
+        // With --paths, name is the path. Without anything to name the device,
+        // we can't use the output at all.
+        test_env.mock(
+            "lsblk",
+            0,
+            r#"{"blockdevices": [{"name": "/dev/nvme1n1", "type": "disk", "tran": "nvme"}]}"#,
+        );
+        assert_eq!(disk_detector.lsblk_all().unwrap()[0].path, "/dev/nvme1n1");
+        test_env.mock("lsblk", 0, r#"{"blockdevices": [{"type": "disk"}]}"#);
+        assert!(matches!(
+            disk_detector.lsblk_all(),
+            Err(Error::ParseError { .. })
+        ));
+    }
+
+    #[test]
+    fn test_detect_gcp_devices_without_links() {
+        let test_env = TestEnv::new();
+        // There is no /dev/disk/by-id at all.
+        let disk_detector = test_detector(&test_env, CloudProvider::Gcp, test_options(&test_env));
+        let lsblk_output = test_env.read_testdata("testdata/gcp/lsblk.json");
         test_env.mock("lsblk", 0, &lsblk_output);
-        let expected: Vec<LsblkBlockDevice> = vec![LsblkBlockDevice {
-            children: None,
-            model: Some("Microsoft NVMe Direct Disk v2           ".to_owned()),
-            mountpoint: None,
-            path: "/dev/nvme0n1".to_owned(),
-            tran: Some("nvme".to_owned()),
-            type_: "disk".to_owned(),
-        }];
-        let actual: Vec<LsblkBlockDevice> = disk_detector.lsblk().collect();
-        assert_eq!(expected, actual);
+        let actual = disk_detector.detect_devices().unwrap();
+        assert_eq!(actual, vec!["/dev/nvme0n1".to_owned()]);
+
+        let lsblk_output = test_env.read_testdata("testdata/gcp/lsblk_scsi.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let actual = disk_detector.detect_devices().unwrap();
+        assert_eq!(actual, vec!["/dev/sdb".to_owned(), "/dev/sdc".to_owned()]);
+
+        // Two local SSD namespaces on one controller,
+        // and a persistent disk of the same size.
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/sysfs/gcp");
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Gcp,
+            DetectOptions {
+                block_device_source: BlockDeviceSource::Sysfs,
+                host_root: HostRoot::new(root),
+                ..Default::default()
+            },
+        );
+        let actual = disk_detector.detect_devices().unwrap();
+        assert_eq!(
+            actual,
+            vec!["/dev/nvme1n1".to_owned(), "/dev/nvme1n2".to_owned()]
+        );
+
+        // Z3 local SSDs are bigger, which we only know from the catalog.
+        let mut lsblk: serde_json::Value =
+            serde_json::from_str(&test_env.read_testdata("testdata/gcp/lsblk.json")).unwrap();
+        lsblk["blockdevices"][1]["size"] = (3u64 << 40).into();
+        test_env.mock("lsblk", 0, &lsblk.to_string());
+        let detector = |instance_metadata| {
+            test_detector(
+                &test_env,
+                CloudProvider::Gcp,
+                DetectOptions {
+                    instance_metadata,
+                    ..test_options(&test_env)
+                },
+            )
+        };
+        let actual = detector(None).detect_devices();
+        assert!(matches!(actual, Err(Error::NoDevices { .. })), "{actual:?}");
+        let actual = detector(Some(InstanceMetadata {
+            instance_type: "z3-highmem-88".to_owned(),
+            ..Default::default()
+        }))
+        .detect_devices()
+        .unwrap();
+        assert_eq!(actual, vec!["/dev/nvme0n1".to_owned()]);
+
+        // When udev made links for other disks, but not for local SSDs,
+        // there aren't any.
+        let host_root = udev_links(
+            &test_env,
+            "/dev/disk/by-id",
+            &[("google-persistent-disk-0", "sda")],
+        );
+        let lsblk_output = test_env.read_testdata("testdata/gcp/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Gcp,
+            DetectOptions {
+                host_root,
+                ..Default::default()
+            },
+        );
+        let actual = disk_detector.detect_devices();
+        assert!(matches!(actual, Err(Error::NoDevices { .. })), "{actual:?}");
+    }
+
+    #[test]
+    fn test_host_disks() {
+        let test_env = TestEnv::new();
+        // In our mount namespace, none of these look used.
+        let lsblk_output = test_env.read_testdata("testdata/lsblk_host_disks.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/host_disks");
+        let detector = |include_devices: Vec<String>| {
+            test_detector(
+                &test_env,
+                CloudProvider::Generic,
+                DetectOptions {
+                    host_root: HostRoot::new(&root),
+                    include_devices,
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(
+            detector(vec![]).detect_devices().unwrap(),
+            vec!["/dev/nvme1n1".to_owned(), "/dev/nvme6n1".to_owned()]
+        );
+        // Even asking for them explicitly doesn't help.
+        let actual =
+            detector(vec!["/dev/nvme0n1".to_owned(), "/dev/nvme7n1".to_owned()]).detect_devices();
+        assert!(matches!(actual, Err(Error::NoDevices { .. })), "{actual:?}");
+
+        let inventory = serde_json::to_value(detector(vec![]).inventory().unwrap()).unwrap();
+        let reasons: Vec<(&str, &str)> = inventory["devices"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|device| device["attributes"]["type"] == "disk")
+            .map(|device| {
+                (
+                    device["path"].as_str().unwrap(),
+                    device["reason"].as_str().unwrap_or_default(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                ("/dev/nvme0n1", "host_disk"),
+                ("/dev/nvme1n1", ""),
+                ("/dev/nvme2n1", "host_disk"),
+                ("/dev/nvme3n1", "host_disk"),
+                ("/dev/nvme4n1", "host_disk"),
+                ("/dev/nvme5n1", "mounted"),
+                ("/dev/nvme6n1", ""),
+                ("/dev/nvme7n1", "host_disk"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("/dev/nvme1n1", "/dev/nvme1n1"));
+        assert!(!glob_match("/dev/nvme1n1", "/dev/nvme11n1"));
+        assert!(glob_match("/dev/nvme*n1", "/dev/nvme11n1"));
+        assert!(glob_match("/dev/nvme?n1", "/dev/nvme1n1"));
+        assert!(!glob_match("/dev/nvme?n1", "/dev/nvme11n1"));
+        assert!(glob_match("*", "/dev/sda"));
+        assert!(glob_match("/dev/*a*b", "/dev/xaxxab"));
+        assert!(!glob_match("/dev/*a*b", "/dev/xaxxa"));
     }
 
     #[test]
     fn test_detect_aws_bottlerocket_devices() {
         let test_env = TestEnv::new();
-        let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Aws);
+        // The host's device nodes are only in its root filesystem.
+        let rootfs = test_env.temp_dir.path().join("rootfs");
+        std::fs::create_dir_all(rootfs.join("dev")).unwrap();
+        let rootfs_device = |name: &str| rootfs.join("dev").join(name).display().to_string();
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Aws,
+            DetectOptions {
+                host_root: HostRoot::new(&rootfs),
+                ..Default::default()
+            },
+        );
 
         let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
         test_env.mock("lsblk", 0, &lsblk_output);
-        let expected = vec!["/.bottlerocket/rootfs/dev/nvme1n1".to_owned()];
-        let actual = disk_detector.detect_aws_bottlerocket_devices();
+        let expected = vec![rootfs_device("nvme1n1")];
+        let actual = disk_detector.detect_devices().unwrap();
         assert_eq!(expected, actual);
 
+        // Disks are set up through their stable links, which are also only
+        // usable from the root filesystem.
+        std::fs::create_dir_all(rootfs.join("dev/disk/by-id")).unwrap();
+        std::fs::write(rootfs.join("dev/nvme1n1"), "").unwrap();
+        let link = "nvme-Amazon_EC2_NVMe_Instance_Storage_AWS1A2B3C4D5E6F7";
+        std::os::unix::fs::symlink("../../nvme1n1", rootfs.join("dev/disk/by-id").join(link))
+            .unwrap();
+        let actual = disk_detector.detect_device_groups().unwrap();
+        let disk = &actual[0].devices[0];
+        assert_eq!(disk.path, rootfs_device("nvme1n1"));
+        assert_eq!(
+            disk.by_id,
+            Some(rootfs_device(&format!("disk/by-id/{link}")))
+        );
+        assert_eq!(disk.stable_path(), disk.by_id.as_deref().unwrap());
+
         let lsblk_output = test_env.read_testdata("testdata/lsblk_contrived.json");
         test_env.mock("lsblk", 0, &lsblk_output);
         let expected = vec![
-            "/.bottlerocket/rootfs/dev/nvme0n1".to_owned(),
-            "/.bottlerocket/rootfs/dev/nvme1n1".to_owned(),
-            "/.bottlerocket/rootfs/dev/nvme7n1".to_owned(),
+            rootfs_device("nvme0n1"),
+            rootfs_device("nvme1n1"),
+            rootfs_device("nvme7n1"),
         ];
-        let actual = disk_detector.detect_aws_bottlerocket_devices();
+        let actual = disk_detector.detect_devices().unwrap();
         assert_eq!(expected, actual);
     }
 
     #[test]
     fn test_detect_aws_standard_devices() {
         let test_env = TestEnv::new();
-        let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Aws);
+        let disk_detector = test_detector(&test_env, CloudProvider::Aws, test_options(&test_env));
 
         let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
         test_env.mock("lsblk", 0, &lsblk_output);
-        let expected = vec!["/dev/nvme1n1".to_owned()];
-        let actual = disk_detector.detect_aws_standard_devices();
+        let expected = ProviderDevices::from(vec!["/dev/nvme1n1".to_owned()]);
+        let actual = disk_detector.detect_aws_devices().unwrap();
         assert_eq!(expected, actual);
 
         let lsblk_output = test_env.read_testdata("testdata/lsblk_contrived.json");
         test_env.mock("lsblk", 0, &lsblk_output);
-        let expected = vec![
+        let expected = ProviderDevices::from(vec![
             "/dev/nvme0n1".to_owned(),
             "/dev/nvme1n1".to_owned(),
             "/dev/nvme7n1".to_owned(),
-        ];
-        let actual = disk_detector.detect_aws_standard_devices();
+        ]);
+        let actual = disk_detector.detect_aws_devices().unwrap();
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_detect_aws_identify_devices() {
+        let test_env = TestEnv::new();
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Aws,
+            DetectOptions {
+                aws_nvme_identify: true,
+                ..test_options(&test_env)
+            },
+        );
+
+        // The identify data decides, not the model lsblk reports.
+        let mut lsblk_output: serde_json::Value =
+            serde_json::from_str(&test_env.read_testdata("testdata/aws/lsblk.json")).unwrap();
+        for device in lsblk_output["blockdevices"].as_array_mut().unwrap() {
+            device["model"] = "Some new model string".into();
+        }
+        test_env.mock("lsblk", 0, &lsblk_output.to_string());
+        let actual = disk_detector.detect_aws_devices().unwrap();
+        assert_eq!(actual.paths, vec!["/dev/nvme1n1".to_owned()]);
+
+        // The detected disks carry the serial and firmware it reports.
+        let disks = disk_detector.detect_disks().unwrap();
+        assert_eq!(
+            disks
+                .iter()
+                .map(|disk| (
+                    disk.path.as_str(),
+                    disk.serial.as_deref(),
+                    disk.firmware.as_deref()
+                ))
+                .collect::<Vec<_>>(),
+            vec![("/dev/nvme1n1", Some("AWS3CEF3078A8D77867C"), Some("0"))]
+        );
+        let inventory = serde_json::to_value(disk_detector.inventory().unwrap()).unwrap();
+        let nvme1n1 = inventory["devices"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|device| device["path"] == "/dev/nvme1n1")
+            .unwrap();
+        assert_eq!(nvme1n1["selected"], true);
+        assert_eq!(nvme1n1["firmware"], "0");
+    }
+
     #[test]
     fn test_detect_azure_devices() {
         let test_env = TestEnv::new();
-        let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Azure);
+        let disk_detector = test_detector(&test_env, CloudProvider::Azure, test_options(&test_env));
 
         let lsblk_output = test_env.read_testdata("testdata/azure/lsblk.json");
         test_env.mock("lsblk", 0, &lsblk_output);
-        let expected = vec!["/dev/nvme0n1".to_owned()];
-        let actual = disk_detector.detect_azure_devices();
+        let expected = ProviderDevices::from(vec!["/dev/nvme0n1".to_owned()]);
+        let actual = disk_detector.detect_azure_devices().unwrap();
         assert_eq!(expected, actual);
 
         let lsblk_output = test_env.read_testdata("testdata/lsblk_contrived.json");
         test_env.mock("lsblk", 0, &lsblk_output);
-        let expected = vec!["/dev/nvme8n1".to_owned()];
-        let actual = disk_detector.detect_azure_devices();
+        let expected = ProviderDevices::from(vec!["/dev/nvme8n1".to_owned()]);
+        let actual = disk_detector.detect_azure_devices().unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_detect_azure_resource_disk() {
+        let test_env = TestEnv::new();
+        let host_root = test_env.temp_dir.path().join("host");
+        let waagent_conf_path = host_root.join("etc/waagent.conf");
+        std::fs::create_dir_all(waagent_conf_path.parent().unwrap()).unwrap();
+        std::fs::create_dir_all(host_root.join("dev")).unwrap();
+        std::fs::write(host_root.join("dev/sdb"), "").unwrap();
+        std::fs::write(
+            &waagent_conf_path,
+            test_env.read_testdata("testdata/azure/waagent.conf"),
+        )
+        .unwrap();
+        // The fixture has the host's mounts under /host.
+        let lsblk_output = test_env
+            .read_testdata("testdata/azure/lsblk_resource_disk.json")
+            .replace("\"/host", &format!("\"{}", host_root.display()));
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let waagent_conf = std::fs::read_to_string(&waagent_conf_path).unwrap();
+
+        // It's mounted, so we don't use it unless asked to.
+        let disk_detector = test_detector(&test_env, CloudProvider::Azure, test_options(&test_env));
+        let actual = disk_detector.detect_azure_devices().unwrap();
+        assert_eq!(ProviderDevices::default(), actual);
+
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Azure,
+            DetectOptions {
+                azure_reclaim_resource_disk: true,
+                host_root: HostRoot::new(&host_root),
+                ..Default::default()
+            },
+        );
+        let link = host_root.join("dev/disk/azure/resource");
+        std::fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink("../../sdb", &link).unwrap();
+        let expected = ProviderDevices {
+            paths: vec!["/dev/sdb".to_owned()],
+            reclaim: vec!["/dev/sdb".to_owned()],
+            ..Default::default()
+        };
+        let actual = disk_detector.detect_azure_devices().unwrap();
+        assert_eq!(expected, actual);
+
+        // A link to a partition, or to a disk we'd never use, isn't followed.
+        std::fs::remove_file(&link).unwrap();
+        std::os::unix::fs::symlink("../../sdb1", &link).unwrap();
+        let actual = disk_detector.detect_azure_devices().unwrap();
+        assert_eq!(ProviderDevices::default(), actual);
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Azure,
+            DetectOptions {
+                azure_reclaim_resource_disk: true,
+                transports: Some(vec!["nvme".to_owned()]),
+                host_root: HostRoot::new(&host_root),
+                ..Default::default()
+            },
+        );
+        std::fs::remove_file(&link).unwrap();
+        std::os::unix::fs::symlink("../../sdb", &link).unwrap();
+        let actual = disk_detector.detect_azure_devices().unwrap();
+        assert_eq!(ProviderDevices::default(), actual);
+
+        // Without the udev link, we find it by where waagent mounted it,
+        // rather than the OS disk or the data disk.
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Azure,
+            DetectOptions {
+                azure_reclaim_resource_disk: true,
+                host_root: HostRoot::new(&host_root),
+                ..Default::default()
+            },
+        );
+        std::fs::remove_file(&link).unwrap();
+        let actual = disk_detector.detect_azure_devices().unwrap();
+        assert_eq!(expected, actual);
+        // Detecting it leaves the host alone.
+        assert_eq!(
+            waagent_conf,
+            std::fs::read_to_string(&waagent_conf_path).unwrap()
+        );
+
+        // Reclaiming it takes it away from waagent, then wipes it.
+        let log = test_env.temp_dir.path().join("commands.log");
+        for command in ["umount", "nsenter", "wipefs", "blockdev"] {
+            test_env.mock_script(
+                command,
+                &format!("echo \"{command} $*\" >> {}\n", log.display()),
+            );
+        }
+        let disks = disk_detector.detect_disks().unwrap();
+        assert!(!log.exists());
+        let sdb = format!("{}/dev/sdb", host_root.display());
+        assert_eq!(
+            disks
+                .iter()
+                .map(|disk| (disk.path.as_str(), disk.reclaim))
+                .collect::<Vec<_>>(),
+            vec![(sdb.as_str(), true)]
+        );
+        disk_detector.reclaim(&disks).unwrap();
+        let conf = std::fs::read_to_string(&waagent_conf_path).unwrap();
+        assert!(conf.contains("\nResourceDisk.Format=n\n"));
+        assert!(conf.contains("\nResourceDisk.EnableSwap=n\n"));
+        assert!(conf.contains("\nResourceDisk.MountPoint=/mnt\n"));
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            format!(
+                "nsenter --target 1 --mount -- swapoff /mnt/swapfile\n\
+                 umount {host}/mnt\n\
+                 nsenter --target 1 --mount -- umount --all-targets /dev/sdb1\n\
+                 nsenter --target 1 --mount -- umount --all-targets /dev/sdb\n\
+                 wipefs --all {sdb}1\n\
+                 wipefs --all {sdb}\n\
+                 blockdev --rereadpt {sdb}\n",
+                host = host_root.display(),
+            )
+        );
+
+        // The NVMe direct disks are preferred.
+        let lsblk_output = test_env.read_testdata("testdata/azure/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let expected = ProviderDevices::from(vec!["/dev/nvme0n1".to_owned()]);
+        let actual = disk_detector.detect_azure_devices().unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_is_alibaba_cloud_disk_serial() {
+        assert!(is_alibaba_cloud_disk_serial("bp1f2nmt5wfzx1l4x3kq"));
+        assert!(is_alibaba_cloud_disk_serial("d-2zef2nmt5wfzx1l4x3kq "));
+        // Local disks, and IDs of the wrong length or region.
+        assert!(!is_alibaba_cloud_disk_serial("ALI0921A00315K7GN"));
+        assert!(!is_alibaba_cloud_disk_serial("phlj9134006j4p0dgn1a"));
+        assert!(!is_alibaba_cloud_disk_serial("bp1f2nmt5wfzx1l4x3k"));
+        assert!(!is_alibaba_cloud_disk_serial("bp1f2nmt5wfzx1l4x3kq0"));
+        assert!(!is_alibaba_cloud_disk_serial("x-bp1f2nmt5wfzx1l4x3kq"));
+    }
+
+    #[test]
+    fn test_waagent_conf_value() {
+        let conf = "# ResourceDisk.Format=y\nResourceDisk.Format=n\n ResourceDisk.MountPoint = /mnt/resource \n";
+        assert_eq!(waagent_conf_value(conf, "ResourceDisk.Format"), Some("n"));
+        assert_eq!(
+            waagent_conf_value(conf, "ResourceDisk.MountPoint"),
+            Some("/mnt/resource")
+        );
+        assert_eq!(waagent_conf_value(conf, "ResourceDisk.EnableSwap"), None);
+    }
+
+    #[test]
+    fn test_detect_oci_devices() {
+        let test_env = TestEnv::new();
+        let disk_detector = test_detector(&test_env, CloudProvider::Oci, test_options(&test_env));
+
+        // Neither the block volumes nor the NVMe disk of an unknown model are used.
+        let lsblk_output = test_env.read_testdata("testdata/oci/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let expected = vec!["/dev/nvme0n1".to_owned(), "/dev/nvme1n1".to_owned()];
+        let actual = disk_detector.detect_oci_devices().unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_detect_alibaba_devices() {
+        let test_env = TestEnv::new();
+        let disk_detector =
+            test_detector(&test_env, CloudProvider::Alibaba, test_options(&test_env));
+
+        let lsblk_output = test_env.read_testdata("testdata/alibaba/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        // Neither the cloud disks nor the NVMe disk of an unknown model are used.
+        let expected = vec!["/dev/nvme3n1".to_owned(), "/dev/nvme4n1".to_owned()];
+        let actual = disk_detector.detect_alibaba_devices().unwrap();
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn test_detect_openstack_devices() {
+        let test_env = TestEnv::new();
+        let disk_detector =
+            test_detector(&test_env, CloudProvider::OpenStack, test_options(&test_env));
+
+        // The ephemeral disk is mounted at /mnt by cloud-init.
+        let lsblk_output = test_env.read_testdata("testdata/openstack/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let actual = disk_detector.detect_openstack_devices().unwrap();
+        assert_eq!(actual, ProviderDevices::default());
+
+        // Detecting it to reclaim doesn't change anything yet.
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::OpenStack,
+            DetectOptions {
+                openstack_reclaim_ephemeral: true,
+                ..test_options(&test_env)
+            },
+        );
+        let actual = disk_detector.detect_openstack_devices().unwrap();
+        assert_eq!(
+            actual,
+            ProviderDevices {
+                paths: vec!["/dev/vdb".to_owned()],
+                reclaim: vec!["/dev/vdb".to_owned()],
+                ..Default::default()
+            }
+        );
+
+        // Without the label, we find it through the config drive,
+        // which we pretend is already mounted at our test data.
+        let mut lsblk_output: serde_json::Value = serde_json::from_str(
+            &test_env.read_testdata("testdata/openstack/lsblk_unlabeled.json"),
+        )
+        .unwrap();
+        for device in lsblk_output["blockdevices"].as_array_mut().unwrap() {
+            if device["label"] == "config-2" {
+                device["mountpoint"] = concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/testdata/openstack/config-drive"
+                )
+                .into();
+            }
+        }
+        test_env.mock("lsblk", 0, &lsblk_output.to_string());
+        let disk_detector =
+            test_detector(&test_env, CloudProvider::OpenStack, test_options(&test_env));
+        let actual = disk_detector.detect_openstack_devices().unwrap();
+        assert_eq!(actual, ProviderDevices::from(vec!["/dev/vdb".to_owned()]));
+
+        // Otherwise, we mount it somewhere of our own for as long as we read it.
+        let mounts = test_env.temp_dir.path().join("mounts");
+        let mount_script = format!(
+            "echo \"$4\" > {mounts}\ncp -r {config_drive}/. \"$4\"\n",
+            mounts = mounts.display(),
+            config_drive = concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/testdata/openstack/config-drive"
+            ),
+        );
+        test_env.mock_script("mount", &mount_script);
+        test_env.mock("umount", 0, "");
+        let lsblk_output = test_env.read_testdata("testdata/openstack/lsblk_unlabeled.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let actual = disk_detector.detect_openstack_devices().unwrap();
+        assert_eq!(actual, ProviderDevices::from(vec!["/dev/vdb".to_owned()]));
+        let mountpoint = std::fs::read_to_string(&mounts).unwrap();
+        assert!(!Path::new(mountpoint.trim()).exists(), "{mountpoint}");
+
+        // Including when mounting it fails.
+        test_env.mock_script(
+            "mount",
+            &format!("echo \"$4\" > {}\nexit 32\n", mounts.display()),
+        );
+        let actual = disk_detector.detect_openstack_devices();
+        assert!(
+            matches!(actual, Err(Error::CommandFailed { .. })),
+            "{actual:?}"
+        );
+        let mountpoint = std::fs::read_to_string(&mounts).unwrap();
+        assert!(!Path::new(mountpoint.trim()).exists(), "{mountpoint}");
+
+        // The usual filters still apply to ephemeral disks.
+        test_env.mock_script("mount", &mount_script);
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::OpenStack,
+            DetectOptions {
+                transports: Some(vec!["nvme".to_owned()]),
+                ..test_options(&test_env)
+            },
+        );
+        let actual = disk_detector.detect_openstack_devices().unwrap();
+        assert_eq!(actual, ProviderDevices::default());
+    }
+
+    #[test]
+    fn test_reclaim_openstack_devices() {
+        let test_env = TestEnv::new();
+        // The host's device nodes are only in its root filesystem,
+        // and the ephemeral disk is mounted at /mnt by cloud-init.
+        let host_root = test_env.temp_dir.path().join("host");
+        std::fs::create_dir_all(host_root.join("dev")).unwrap();
+        for name in ["vda", "vdb", "vdc"] {
+            std::fs::write(host_root.join("dev").join(name), "").unwrap();
+        }
+        let host_root = HostRoot::new(&host_root);
+        let lsblk_output = test_env.read_testdata("testdata/openstack/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let log = test_env.temp_dir.path().join("commands.log");
+        for command in ["umount", "nsenter", "wipefs", "blockdev"] {
+            test_env.mock_script(
+                command,
+                &format!("echo \"{command} $*\" >> {}\n", log.display()),
+            );
+        }
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::OpenStack,
+            DetectOptions {
+                openstack_reclaim_ephemeral: true,
+                host_root: host_root.clone(),
+                ..Default::default()
+            },
+        );
+
+        // Nothing happens until the disks are about to be set up.
+        let disks = disk_detector.detect_disks().unwrap();
+        assert!(!log.exists());
+        let vdb = host_root.device("/dev/vdb");
+        assert_eq!(
+            disks
+                .iter()
+                .map(|disk| (disk.path.as_str(), disk.reclaim))
+                .collect::<Vec<_>>(),
+            vec![(vdb.as_str(), true)]
+        );
+        disk_detector.reclaim(&disks).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            format!(
+                "umount /host/mnt\n\
+                 nsenter --target 1 --mount -- umount --all-targets /dev/vdb\n\
+                 wipefs --all {vdb}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_read_config_drive_ephemeral_names() {
+        let config_drive =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/openstack/config-drive");
+        assert_eq!(
+            read_config_drive_ephemeral_names(&config_drive).unwrap(),
+            vec!["vdb".to_owned()]
+        );
+        assert_eq!(
+            read_config_drive_ephemeral_names(Path::new("/nonexistent")).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
     #[test]
     fn test_detect_gcp_devices() {
         let test_env = TestEnv::new();
-        let disk_detector = DiskDetector::new(test_env.commander.clone(), CloudProvider::Gcp);
+        let detector = |host_root| {
+            test_detector(
+                &test_env,
+                CloudProvider::Gcp,
+                DetectOptions {
+                    host_root,
+                    ..Default::default()
+                },
+            )
+        };
 
         let lsblk_output = test_env.read_testdata("testdata/gcp/lsblk.json");
         test_env.mock("lsblk", 0, &lsblk_output);
-        // For testing, we evaluate the links to
-        // /dev/nvme{last_char_from_find_line}n1
-        test_env.mock(
-            "find",
-            0,
-            r#"/dev/disk/by-id/google-local-nvme-ssd-1
-/dev/disk/by-id/google-local-ssd-block0
-/dev/disk/by-id/google-persistent-disk-0
-/dev/disk/by-id/google-persistent-disk-0-part3
-/dev/disk/by-id/google-persistent-disk-0-part4
-/dev/disk/by-id/nvme-nvme_card_nvme_card5
-"#,
+        let host_root = udev_links(
+            &test_env,
+            "/dev/disk/by-id",
+            &[
+                ("google-local-nvme-ssd-0", "nvme0n1"),
+                ("google-persistent-disk-0", "sda"),
+                ("google-persistent-disk-0-part3", "sda3"),
+                ("google-persistent-disk-0-part4", "sda4"),
+                ("nvme-nvme_card_nvme_card5", "nvme0n1"),
+            ],
         );
         let expected = vec!["/dev/nvme0n1".to_owned()];
-        let actual = disk_detector.detect_gcp_devices();
+        let actual = detector(host_root).detect_gcp_devices().unwrap();
         assert_eq!(expected, actual);
 
-        test_env.mock(
-            "find",
-            0,
-            // We sort these before comparing with lsblk
-            r#"/dev/disk/by-id/google-local-ssd-block9
-/dev/disk/by-id/google-local-ssd-doesnt-match-lsblk4
-/dev/disk/by-id/google-local-ssd-ok-lsblk2
-"#,
+        let host_root = udev_links(
+            &test_env,
+            "/dev/disk/by-id",
+            &[
+                ("google-local-ssd-block9", "nvme9n1"),
+                ("google-local-ssd-doesnt-match-lsblk4", "nvme4n1"),
+                ("google-local-ssd-ok-lsblk2", "nvme2n1"),
+            ],
         );
         let lsblk_output = test_env.read_testdata("testdata/lsblk_contrived.json");
         test_env.mock("lsblk", 0, &lsblk_output);
         let expected = vec!["/dev/nvme2n1".to_owned(), "/dev/nvme9n1".to_owned()];
-        let actual = disk_detector.detect_gcp_devices();
+        let actual = detector(host_root).detect_gcp_devices().unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_detect_gcp_scsi_devices() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/gcp/lsblk_scsi.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        // SCSI local SSDs come after the sda boot disk.
+        let host_root = udev_links(
+            &test_env,
+            "/dev/disk/by-id",
+            &[("google-local-ssd-0", "sdb"), ("google-local-ssd-1", "sdc")],
+        );
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Gcp,
+            DetectOptions {
+                host_root: host_root.clone(),
+                ..Default::default()
+            },
+        );
+        let expected = vec![host_root.device("/dev/sdb"), host_root.device("/dev/sdc")];
+        let actual = disk_detector.detect_devices().unwrap();
+        assert_eq!(expected, actual);
+
+        // They are excluded if the user only allows NVMe.
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Gcp,
+            DetectOptions {
+                transports: Some(vec!["nvme".to_owned()]),
+                host_root,
+                ..Default::default()
+            },
+        );
+        let expected: Vec<String> = vec![];
+        let actual = disk_detector.detect_gcp_devices().unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_transport_policy() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/virtio/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+
+        // Only NVMe by default.
+        let disk_detector =
+            test_detector(&test_env, CloudProvider::Generic, test_options(&test_env));
+        let expected: Vec<String> = vec![];
+        let actual = disk_detector.detect_generic_devices().unwrap();
+        assert_eq!(expected, actual);
+
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Generic,
+            DetectOptions {
+                transports: Some(vec!["nvme".to_owned(), "virtio".to_owned()]),
+                ..test_options(&test_env)
+            },
+        );
+        let expected = vec!["/dev/vdb".to_owned()];
+        let actual = disk_detector.detect_generic_devices().unwrap();
+        assert_eq!(expected, actual);
+
+        // An explicit list still applies alongside a selector.
+        let disk_detector = test_detector(
+            &test_env,
+            CloudProvider::Generic,
+            DetectOptions {
+                selector: Some("size>=100GiB".parse().unwrap()),
+                transports: Some(vec!["sata".to_owned()]),
+                ..test_options(&test_env)
+            },
+        );
+        let expected: Vec<String> = vec![];
+        let actual = disk_detector.detect_generic_devices().unwrap();
         assert_eq!(expected, actual);
     }
 }
@@ -0,0 +1,319 @@
+//! Disks the host runs from: its root and boot filesystems, swap, and RAID
+//! members, plus anything else it has mounted.
+//!
+//! lsblk in a container only sees the container's mounts, so a disk the host
+//! uses can look free. We read the host's own view instead, and never use
+//! those disks, whichever provider detected them.
+//!
+//! Whole disks in `/proc/swaps` are the exception: that's how we set up swap,
+//! so swap recognizes them as already configured, and LVM finds them in use.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::detect::sysfs::{list_dir, parse_dev_number, unescape_mountinfo};
+use crate::detect::{Exclusion, ExclusionReason};
+use crate::host_root::HostRoot;
+
+// The mounts of the host's init. Reading this needs the host PID namespace,
+// or the host's /proc under the host root.
+const HOST_MOUNTINFO_PATH: &str = "/proc/1/mountinfo";
+const SWAPS_PATH: &str = "/proc/swaps";
+const MDSTAT_PATH: &str = "/proc/mdstat";
+// Filesystems the host needs to boot, besides its root.
+const BOOT_MOUNTPOINTS: &[&str] = &["/boot", "/boot/efi"];
+
+/// Disks the host uses, by kernel name.
+#[derive(Debug, Default)]
+pub(super) struct HostDisks {
+    // Disks the host needs to run, with why.
+    system: HashMap<String, String>,
+    // Other disks the host has mounted, with where.
+    mounted: HashMap<String, String>,
+}
+
+impl HostDisks {
+    pub(super) fn read(host_root: &HostRoot) -> HostDisks {
+        let read = |path: &str| {
+            let path = host_root.path(path);
+            fs::read_to_string(&path)
+                .inspect_err(|e| debug!("Failed to read '{}': {e}", path.display()))
+                .unwrap_or_default()
+        };
+        let mut host_disks = HostDisks::default();
+        for mount in parse_mountinfo(&read(HOST_MOUNTINFO_PATH)) {
+            let Some(name) = resolve_dev_number(host_root, mount.dev).or_else(|| {
+                mount
+                    .source
+                    .strip_prefix("/dev/")
+                    .map(|name| name.to_owned())
+            }) else {
+                continue;
+            };
+            let reason = if mount.mountpoint == "/" {
+                Some("the host's root filesystem is on it".to_owned())
+            } else if BOOT_MOUNTPOINTS.contains(&mount.mountpoint.as_str()) {
+                Some(format!(
+                    "the host boots from it, with '{}'",
+                    mount.mountpoint
+                ))
+            } else {
+                None
+            };
+            for disk in disks(host_root, &name) {
+                match &reason {
+                    Some(reason) => host_disks.system.entry(disk).or_insert(reason.clone()),
+                    None => host_disks
+                        .mounted
+                        .entry(disk)
+                        .or_insert(mount.mountpoint.clone()),
+                };
+            }
+        }
+        for name in parse_swaps(&read(SWAPS_PATH)) {
+            let disks = disks(host_root, &name);
+            if disks == [name.clone()] {
+                debug!("Leaving whole disk swap '/dev/{name}' to the controllers");
+                continue;
+            }
+            for disk in disks {
+                host_disks
+                    .system
+                    .entry(disk)
+                    .or_insert(format!("the host swaps to '/dev/{name}'"));
+            }
+        }
+        for (array, members) in parse_mdstat(&read(MDSTAT_PATH)) {
+            for member in members {
+                for disk in disks(host_root, &member) {
+                    host_disks
+                        .system
+                        .entry(disk)
+                        .or_insert(format!("it is a member of RAID array '{array}'"));
+                }
+            }
+        }
+        debug!("Host disks: {host_disks:?}");
+        host_disks
+    }
+
+    /// Why the host keeps us from using a device, like `/dev/nvme0n1`, if it does.
+    pub(super) fn exclusion(&self, device: &str) -> Option<Exclusion> {
+        let name = Path::new(device).file_name()?.to_str()?;
+        if let Some(reason) = self.system.get(name) {
+            return Some(Exclusion::new(ExclusionReason::HostDisk, reason.clone()));
+        }
+        self.mounted.get(name).map(|mountpoint| {
+            Exclusion::new(
+                ExclusionReason::Mounted,
+                format!("the host mounts it at '{mountpoint}'"),
+            )
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Mount {
+    dev: (u32, u32),
+    // What was mounted, like /dev/nvme0n1p1, or overlay.
+    source: String,
+    mountpoint: String,
+}
+
+fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    // Lines look like:
+    // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+    // with any number of optional fields before the separator.
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (mount, filesystem) = line.split_once(" - ")?;
+            let mount: Vec<&str> = mount.split_whitespace().collect();
+            let source = filesystem.split_whitespace().nth(1)?;
+            Some(Mount {
+                dev: parse_dev_number(mount.get(2)?)?,
+                source: unescape_mountinfo(source),
+                mountpoint: unescape_mountinfo(mount.get(4)?),
+            })
+        })
+        .collect()
+}
+
+/// Kernel names of the partitions and disks the host swaps to.
+/// Swap files are on a mounted filesystem, so we already know about them.
+fn parse_swaps(swaps: &str) -> Vec<String> {
+    // Contents look like:
+    // Filename                                Type            Size            Used            Priority
+    // /dev/nvme1n1                            partition       8388604         0               -2
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [filename, "partition", ..] => filename.strip_prefix("/dev/").map(str::to_owned),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// RAID arrays, and the kernel names of their members.
+fn parse_mdstat(mdstat: &str) -> Vec<(String, Vec<String>)> {
+    // Arrays look like:
+    // md127 : active raid1 nvme3n1[1] nvme2n1[0](F)
+    //       1953382464 blocks super 1.2 [2/2] [UU]
+    mdstat
+        .lines()
+        .filter_map(|line| {
+            let (array, status) = line.split_once(" : ")?;
+            if !array.starts_with("md") {
+                return None;
+            }
+            let members = status
+                .split_whitespace()
+                .filter_map(|field| field.split_once('['))
+                .map(|(member, _)| member.to_owned())
+                .collect();
+            Some((array.trim().to_owned(), members))
+        })
+        .collect()
+}
+
+/// The kernel name of a block device, from its device number.
+fn resolve_dev_number(host_root: &HostRoot, (major, minor): (u32, u32)) -> Option<String> {
+    let link = host_root.path(format!("/sys/dev/block/{major}:{minor}"));
+    let path = fs::canonicalize(link).ok()?;
+    Some(path.file_name()?.to_str()?.to_owned())
+}
+
+/// The disks a block device is on: the device itself, a partition's disk,
+/// or whatever is under a device mapper or md device.
+fn disks(host_root: &HostRoot, name: &str) -> Vec<String> {
+    let dir = host_root.path(format!("/sys/class/block/{name}"));
+    let slaves = list_dir(&dir.join("slaves"));
+    if !slaves.is_empty() {
+        return slaves
+            .iter()
+            .flat_map(|slave| disks(host_root, slave))
+            .collect();
+    }
+    if dir.join("partition").exists() {
+        // Partitions are in their disk's directory.
+        let disk = fs::canonicalize(&dir)
+            .ok()
+            .and_then(|path| Some(path.parent()?.file_name()?.to_str()?.to_owned()));
+        if let Some(disk) = disk {
+            return vec![disk];
+        }
+    }
+    vec![name.to_owned()]
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::detect::ExclusionReason;
+    use crate::detect::host_disks::{HostDisks, Mount, parse_mdstat, parse_mountinfo};
+    use crate::host_root::HostRoot;
+
+    #[test]
+    fn test_host_disks() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/host_disks");
+        let host_disks = HostDisks::read(&HostRoot::new(root));
+        let exclusion = |device: &str| {
+            host_disks
+                .exclusion(device)
+                .map(|exclusion| (exclusion.reason, exclusion.detail))
+        };
+        assert_eq!(
+            exclusion("/dev/nvme0n1"),
+            Some((
+                ExclusionReason::HostDisk,
+                "the host's root filesystem is on it".to_owned()
+            ))
+        );
+        assert_eq!(
+            exclusion("/dev/nvme7n1"),
+            Some((
+                ExclusionReason::HostDisk,
+                "the host swaps to '/dev/nvme7n1p2'".to_owned()
+            ))
+        );
+        // Like we set swap up, so it's left to the controllers.
+        assert_eq!(exclusion("/dev/nvme1n1"), None);
+        for device in ["/dev/nvme2n1", "/dev/nvme3n1"] {
+            assert_eq!(
+                exclusion(device),
+                Some((
+                    ExclusionReason::HostDisk,
+                    "it is a member of RAID array 'md127'".to_owned()
+                ))
+            );
+        }
+        // Mounted through a partition, so the whole disk is the host's.
+        assert_eq!(
+            exclusion("/dev/nvme4n1"),
+            Some((
+                ExclusionReason::HostDisk,
+                "the host boots from it, with '/boot'".to_owned()
+            ))
+        );
+        // Mounted through LVM.
+        assert_eq!(
+            exclusion("/dev/nvme5n1"),
+            Some((
+                ExclusionReason::Mounted,
+                "the host mounts it at '/var/lib/data'".to_owned()
+            ))
+        );
+        assert_eq!(exclusion("/dev/nvme6n1"), None);
+
+        // Nothing to read at all.
+        let host_disks = HostDisks::read(&HostRoot::new("/nonexistent"));
+        assert!(host_disks.exclusion("/dev/nvme0n1").is_none());
+    }
+
+    #[test]
+    fn test_parse_host_files() {
+        assert_eq!(
+            parse_mountinfo(
+                "22 1 259:3 / /var/lib/my\\040data rw,relatime shared:5 master:1 - xfs /dev/nvme0n1p3 rw\n\
+                 23 22 0:21 / /proc rw - proc proc rw\n\
+                 garbage\n"
+            ),
+            vec![
+                Mount {
+                    dev: (259, 3),
+                    source: "/dev/nvme0n1p3".to_owned(),
+                    mountpoint: "/var/lib/my data".to_owned(),
+                },
+                Mount {
+                    dev: (0, 21),
+                    source: "proc".to_owned(),
+                    mountpoint: "/proc".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            parse_mdstat(
+                "Personalities : [raid1] [raid0]\n\
+                 md0 : active raid0 sdc1[1] sdb1[0]\n      \
+                 20953088 blocks super 1.2 512k chunks\n\n\
+                 md1 : active raid1 nvme2n1[0](F) nvme3n1[1]\n\
+                 unused devices: <none>\n"
+            ),
+            vec![
+                ("md0".to_owned(), vec!["sdc1".to_owned(), "sdb1".to_owned()]),
+                (
+                    "md1".to_owned(),
+                    vec!["nvme2n1".to_owned(), "nvme3n1".to_owned()]
+                ),
+            ]
+        );
+    }
+}
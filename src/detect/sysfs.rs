@@ -0,0 +1,369 @@
+//! Builds the same block device records lsblk reports, straight from sysfs
+//! and mountinfo, so we don't depend on which version of lsblk we have.
+//!
+//! Filesystem labels aren't available from sysfs, so they are never set.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tracing::trace;
+
+use crate::detect::LsblkBlockDevice;
+
+/// Our own mount table, which is the one lsblk reads,
+/// so mountpoints are where we see them, not where the host does.
+pub(super) const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+/// Returns all block devices under `root`/sys/block, with partitions and
+/// holders as children, ordered by device number like lsblk does,
+/// and their mountpoints from `mountinfo`.
+pub(super) fn block_devices(root: &Path, mountinfo: &Path) -> io::Result<Vec<LsblkBlockDevice>> {
+    let mountpoints = mountpoints(mountinfo);
+    let sys_block = root.join("sys/block");
+    let mut devices = vec![];
+    for entry in fs::read_dir(&sys_block)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let dir = sys_block.join(&name);
+        // lsblk hides RAM disks, and only shows device mapper and md devices
+        // as children of the devices they are built on.
+        if name.starts_with("ram") || !list_dir(&dir.join("slaves")).is_empty() {
+            continue;
+        }
+        devices.push((
+            dev_number(&dir),
+            block_device(&sys_block, &name, &mountpoints),
+        ));
+    }
+    devices.sort_by_key(|(dev, _)| *dev);
+    trace!("sysfs block devices:\n{devices:#?}");
+    Ok(devices.into_iter().map(|(_, device)| device).collect())
+}
+
+fn block_device(
+    sys_block: &Path,
+    name: &str,
+    mountpoints: &HashMap<(u32, u32), Vec<String>>,
+) -> LsblkBlockDevice {
+    let dir = sys_block.join(name);
+    let device_dir = dir.join("device");
+    let mut children: Vec<LsblkBlockDevice> = list_dir(&dir)
+        .into_iter()
+        .filter(|child| dir.join(child).join("partition").exists())
+        .map(|child| partition(sys_block, &dir.join(&child), &child, mountpoints))
+        .collect();
+    children.sort_by_key(|child| child.path.clone());
+    children.extend(holders(sys_block, &dir, mountpoints));
+
+    let (path, type_) = if name.starts_with("dm-") {
+        let dm_name = read(&dir.join("dm/name")).unwrap_or_else(|| name.to_owned());
+        let is_lvm = read(&dir.join("dm/uuid")).is_some_and(|uuid| uuid.starts_with("LVM-"));
+        (
+            format!("/dev/mapper/{dm_name}"),
+            if is_lvm { "lvm" } else { "dm" }.to_owned(),
+        )
+    } else {
+        let type_ = if name.starts_with("sr") {
+            "rom".to_owned()
+        } else if name.starts_with("loop") {
+            "loop".to_owned()
+        } else if name.starts_with("md") {
+            read(&dir.join("md/level")).unwrap_or_else(|| "md".to_owned())
+        } else {
+            "disk".to_owned()
+        };
+        // Nested device names use '!' in sysfs where /dev uses '/'.
+        (format!("/dev/{}", name.replace('!', "/")), type_)
+    };
+
+    LsblkBlockDevice {
+        children: Some(children),
+        label: None,
+        model: read_raw(&device_dir.join("model")),
+        mountpoints: mountpoints
+            .get(&dev_number(&dir))
+            .cloned()
+            .unwrap_or_default(),
+        serial: read(&device_dir.join("serial")),
+        path,
+        tran: transport(&dir, name),
+        type_,
+        vendor: read_raw(&device_dir.join("vendor")),
+        wwn: read(&dir.join("wwid"))
+            .or_else(|| read(&device_dir.join("wwid")))
+            .map(|wwid| match wwid.strip_prefix("naa.") {
+                // lsblk reports SCSI WWNs in hex rather than NAA form.
+                Some(naa) => format!("0x{naa}"),
+                None => wwid,
+            }),
+        size: size(&dir),
+        rota: read(&dir.join("queue/rotational")).map(|rota| rota != "0"),
+    }
+}
+
+fn partition(
+    sys_block: &Path,
+    dir: &Path,
+    name: &str,
+    mountpoints: &HashMap<(u32, u32), Vec<String>>,
+) -> LsblkBlockDevice {
+    let parent = dir.parent().unwrap();
+    let parent_name = parent.file_name().unwrap().to_string_lossy();
+    let children = holders(sys_block, dir, mountpoints);
+    LsblkBlockDevice {
+        children: Some(children),
+        label: None,
+        model: None,
+        mountpoints: mountpoints
+            .get(&dev_number(dir))
+            .cloned()
+            .unwrap_or_default(),
+        serial: None,
+        path: format!("/dev/{}", name.replace('!', "/")),
+        // lsblk inherits the transport from the parent disk.
+        tran: transport(parent, &parent_name),
+        type_: "part".to_owned(),
+        vendor: None,
+        wwn: None,
+        size: size(dir),
+        rota: read(&parent.join("queue/rotational")).map(|rota| rota != "0"),
+    }
+}
+
+fn holders(
+    sys_block: &Path,
+    dir: &Path,
+    mountpoints: &HashMap<(u32, u32), Vec<String>>,
+) -> Vec<LsblkBlockDevice> {
+    list_dir(&dir.join("holders"))
+        .into_iter()
+        .map(|holder| block_device(sys_block, &holder, mountpoints))
+        .collect()
+}
+
+/// Works out the transport the same way lsblk does,
+/// from the kind of bus the device hangs off.
+fn transport(dir: &Path, name: &str) -> Option<String> {
+    if name.starts_with("nvme") {
+        return Some("nvme".to_owned());
+    }
+    if name.starts_with("vd") {
+        return Some("virtio".to_owned());
+    }
+    let path = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned());
+    let path = path.to_string_lossy();
+    let tran = if path.contains("/usb") {
+        "usb"
+    } else if path.contains("/ata") {
+        "sata"
+    } else if path.contains("/session") {
+        "iscsi"
+    } else if path.contains("/end_device-") {
+        "sas"
+    } else if path.contains("/rport-") {
+        "fc"
+    } else {
+        return None;
+    };
+    Some(tran.to_owned())
+}
+
+fn size(dir: &Path) -> Option<u64> {
+    // Always in 512 byte sectors, regardless of the device's sector size.
+    read(&dir.join("size"))
+        .and_then(|sectors| sectors.parse::<u64>().ok())
+        .map(|sectors| sectors * 512)
+}
+
+fn dev_number(dir: &Path) -> (u32, u32) {
+    read(&dir.join("dev"))
+        .and_then(|dev| parse_dev_number(&dev))
+        .unwrap_or_default()
+}
+
+/// The NUMA node a disk's controller is attached to, from the nearest
+/// device above it with one, as there is none on the disk itself.
+/// The kernel reports -1 when it doesn't know.
+pub(super) fn numa_node(root: &Path, name: &str) -> Option<u32> {
+    let sys = root.join("sys");
+    let device = fs::canonicalize(sys.join("block").join(name).join("device")).ok()?;
+    device
+        .ancestors()
+        .take_while(|dir| dir.starts_with(&sys))
+        .find_map(|dir| read(&dir.join("numa_node")))
+        .and_then(|node| node.parse().ok())
+}
+
+pub(super) fn parse_dev_number(dev: &str) -> Option<(u32, u32)> {
+    let (major, minor) = dev.split_once(':')?;
+    Some((major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Maps device numbers to their mountpoints, in the order lsblk lists them.
+fn mountpoints(mountinfo: &Path) -> HashMap<(u32, u32), Vec<String>> {
+    let mut mountpoints: HashMap<(u32, u32), Vec<String>> = HashMap::new();
+    // Lines look like:
+    // 36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+    for line in fs::read_to_string(mountinfo).unwrap_or_default().lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (Some(dev), Some(mountpoint)) = (fields.get(2), fields.get(4)) else {
+            continue;
+        };
+        if let Some(dev) = parse_dev_number(dev) {
+            mountpoints
+                .entry(dev)
+                .or_default()
+                .push(unescape_mountinfo(mountpoint));
+        }
+    }
+    mountpoints
+}
+
+/// mountinfo escapes spaces, tabs, newlines and backslashes as octal.
+pub(super) fn unescape_mountinfo(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        let escaped = rest.get(i + 1..i + 4);
+        match escaped.and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+pub(crate) fn list_dir(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Reads a sysfs attribute, keeping any padding but not the trailing newline.
+fn read_raw(path: &PathBuf) -> Option<String> {
+    let value = fs::read_to_string(path).ok()?;
+    let value = value.strip_suffix('\n').unwrap_or(&value);
+    if value.trim().is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+/// Reads a sysfs attribute, trimming whitespace.
+fn read(path: &PathBuf) -> Option<String> {
+    read_raw(path).map(|value| value.trim().to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::detect::LsblkBlockDevice;
+    use crate::detect::sysfs::{block_devices, numa_node, unescape_mountinfo};
+
+    #[test]
+    fn test_sysfs_block_devices() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/sysfs/aws");
+        let devices = block_devices(&root, &root.join("proc/self/mountinfo")).unwrap();
+        let paths: Vec<&str> = devices.iter().map(|device| device.path.as_str()).collect();
+        assert_eq!(paths, vec!["/dev/nvme0n1", "/dev/nvme1n1"]);
+
+        let root_disk = &devices[0];
+        let partitions: Vec<(&str, &[String])> = root_disk
+            .children
+            .iter()
+            .flatten()
+            .map(|child| (child.path.as_str(), child.mountpoints.as_slice()))
+            .collect();
+        assert_eq!(
+            partitions,
+            vec![
+                (
+                    "/dev/nvme0n1p1",
+                    &[
+                        "/etc/hosts".to_owned(),
+                        "/dev/termination-log".to_owned(),
+                        "/etc/hostname".to_owned(),
+                        "/etc/resolv.conf".to_owned(),
+                    ][..]
+                ),
+                ("/dev/nvme0n1p128", &[][..]),
+            ]
+        );
+
+        assert_eq!(
+            devices[1],
+            LsblkBlockDevice {
+                children: Some(vec![]),
+                label: None,
+                model: Some("Amazon EC2 NVMe Instance Storage        ".to_owned()),
+                mountpoints: vec![],
+                serial: Some("AWS3CEF3078A8D77867C".to_owned()),
+                path: "/dev/nvme1n1".to_owned(),
+                tran: Some("nvme".to_owned()),
+                type_: "disk".to_owned(),
+                vendor: None,
+                wwn: Some("nvme.1d0f-4157533343454633303738413844373738363743-416d617a6f6e20454332204e564d6520496e7374616e63652053746f72616765-00000001".to_owned()),
+                size: Some(473949640704),
+                rota: Some(false),
+            }
+        );
+
+        // From the PCI device above the controller.
+        assert_eq!(numa_node(&root, "nvme1n1"), Some(0));
+        // The kernel doesn't know.
+        assert_eq!(numa_node(&root, "nvme0n1"), None);
+        assert_eq!(numa_node(&root, "nvme9n1"), None);
+
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata/sysfs/azure");
+        let devices = block_devices(&root, &root.join("proc/self/mountinfo")).unwrap();
+        let summary: Vec<(&str, &str, Option<&str>, Option<&str>)> = devices
+            .iter()
+            .map(|device| {
+                (
+                    device.path.as_str(),
+                    device.type_.as_str(),
+                    device.tran.as_deref(),
+                    device.vendor.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("/dev/sda", "disk", None, Some("Msft    ")),
+                ("/dev/sr0", "rom", None, Some("Msft    ")),
+                ("/dev/nvme0n1", "disk", Some("nvme"), None),
+            ]
+        );
+        assert_eq!(
+            devices[0].wwn.as_deref(),
+            Some("0x600224809a3a2b5b6c5d4e3f2a1b0c9d")
+        );
+
+        let nonexistent = root.join("nonexistent");
+        assert!(block_devices(&nonexistent, &nonexistent.join("proc/self/mountinfo")).is_err());
+    }
+
+    #[test]
+    fn test_unescape_mountinfo() {
+        assert_eq!(unescape_mountinfo("/mnt/with\\040space"), "/mnt/with space");
+        assert_eq!(unescape_mountinfo("/plain"), "/plain");
+        assert_eq!(unescape_mountinfo("/trailing\\"), "/trailing\\");
+    }
+}
@@ -0,0 +1,184 @@
+//! Stable names for disks, from the links udev makes in `/dev/disk/by-id`.
+//!
+//! Kernel names like `/dev/nvme1n1` are handed out in the order devices are
+//! probed, which can change across reboots, so we set disks up, and later
+//! recognize them, by these links instead.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::host_root::HostRoot;
+
+const DISK_BY_ID_DIR: &str = "/dev/disk/by-id";
+
+/// The most stable `/dev/disk/by-id` link of each disk, by kernel name.
+#[derive(Debug, Default)]
+pub(crate) struct DiskIds {
+    links: HashMap<String, String>,
+    // Kernel names of all the links, by link name.
+    names: HashMap<String, String>,
+}
+
+impl DiskIds {
+    pub(crate) fn read(host_root: &HostRoot) -> DiskIds {
+        let dir = host_root.path(DISK_BY_ID_DIR);
+        let mut disk_ids = DiskIds::default();
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                debug!("Failed to read '{}': {e}", dir.display());
+                return disk_ids;
+            }
+        };
+        for entry in entries.flatten() {
+            let link = entry.file_name().to_string_lossy().into_owned();
+            // We only use whole disks.
+            if link.contains("-part") {
+                continue;
+            }
+            // Links are relative, like ../../nvme1n1.
+            let Some(name) = fs::read_link(entry.path())
+                .ok()
+                .and_then(|target| Some(target.file_name()?.to_str()?.to_owned()))
+            else {
+                continue;
+            };
+            let path = format!("{DISK_BY_ID_DIR}/{link}");
+            // The links have to be usable from where we run commands too.
+            if !Path::new(&host_root.device(&path)).exists() {
+                continue;
+            }
+            disk_ids.names.insert(link, name.clone());
+            match disk_ids.links.get(&name) {
+                Some(best) if rank(best) <= rank(&path) => {}
+                _ => {
+                    disk_ids.links.insert(name, path);
+                }
+            }
+        }
+        debug!("Stable disk links: {:?}", disk_ids.links);
+        disk_ids
+    }
+
+    /// The kernel name of a device, given as a path like `/dev/nvme1n1`,
+    /// any of its `/dev/disk/by-id` links, or as `/proc/swaps` sometimes
+    /// reports it, without the `/dev`.
+    fn kernel_name<'a>(&'a self, device: &'a str) -> Option<&'a str> {
+        let name = Path::new(device).file_name()?.to_str()?;
+        Some(self.names.get(name).map_or(name, String::as_str))
+    }
+
+    /// The stable link of a device, given any way `kernel_name` understands.
+    pub(crate) fn by_id(&self, device: &str) -> Option<&str> {
+        self.links
+            .get(self.kernel_name(device)?)
+            .map(String::as_str)
+    }
+
+    /// Whether two device paths are the same disk,
+    /// by their stable links when both have one, or else their kernel names.
+    pub(crate) fn same_disk(&self, a: &str, b: &str) -> bool {
+        match (self.by_id(a), self.by_id(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.kernel_name(a) == self.kernel_name(b),
+        }
+    }
+}
+
+/// Which link to prefer, lowest first: names the provider picked, then
+/// model and serial, then the identifiers the device reports, like WWNs.
+/// Ties go to the first by name, so we always pick the same one.
+fn rank(path: &str) -> (u8, &str) {
+    let link = path.rsplit('/').next().unwrap_or(path);
+    let rank = if link.starts_with("google-") {
+        0
+    } else if link.starts_with("wwn-")
+        || link.starts_with("nvme-eui.")
+        || link.starts_with("nvme-nvme.")
+    {
+        2
+    } else {
+        1
+    };
+    (rank, link)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use crate::disk_ids::DiskIds;
+    use crate::host_root::HostRoot;
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_disk_ids() {
+        let test_env = TestEnv::new();
+        let root = test_env.temp_dir.path();
+        let by_id = root.join("dev/disk/by-id");
+        fs::create_dir_all(&by_id).unwrap();
+        for name in ["nvme0n1", "nvme0n1p1", "nvme1n1", "nvme2n1", "sdb"] {
+            fs::write(root.join("dev").join(name), "").unwrap();
+        }
+        for (link, target) in [
+            (
+                "nvme-Amazon_Elastic_Block_Store_vol0123456789abcdef0",
+                "nvme0n1",
+            ),
+            (
+                "nvme-Amazon_Elastic_Block_Store_vol0123456789abcdef0-part1",
+                "nvme0n1p1",
+            ),
+            (
+                "nvme-nvme.1d0f-766f6c30313233-416d617a6f6e-00000001",
+                "nvme0n1",
+            ),
+            ("nvme-nvme.1d0f-4157533141-416d617a6f6e-00000001", "nvme1n1"),
+            (
+                "nvme-Amazon_EC2_NVMe_Instance_Storage_AWS1A2B3C4D5E6F7",
+                "nvme1n1",
+            ),
+            ("wwn-0x5000c500a1b2c3d4", "nvme2n1"),
+            ("google-local-ssd-0", "sdb"),
+            ("scsi-0Google_EphemeralDisk_local-ssd-0", "sdb"),
+            // Broken links are left out.
+            ("nvme-gone", "nvme9n1"),
+        ] {
+            symlink(format!("../../{target}"), by_id.join(link)).unwrap();
+        }
+        // Like Bottlerocket, where the links are only usable under the root.
+        let disk_ids = DiskIds::read(&HostRoot::new(root));
+        assert_eq!(
+            disk_ids.by_id("/dev/nvme0n1"),
+            Some("/dev/disk/by-id/nvme-Amazon_Elastic_Block_Store_vol0123456789abcdef0")
+        );
+        assert_eq!(
+            disk_ids.by_id("/nvme1n1"),
+            Some("/dev/disk/by-id/nvme-Amazon_EC2_NVMe_Instance_Storage_AWS1A2B3C4D5E6F7")
+        );
+        assert_eq!(
+            disk_ids.by_id("/dev/nvme2n1"),
+            Some("/dev/disk/by-id/wwn-0x5000c500a1b2c3d4")
+        );
+        assert_eq!(
+            disk_ids.by_id("/dev/sdb"),
+            Some("/dev/disk/by-id/google-local-ssd-0")
+        );
+        assert_eq!(
+            disk_ids.by_id("/dev/disk/by-id/nvme-nvme.1d0f-4157533141-416d617a6f6e-00000001"),
+            Some("/dev/disk/by-id/nvme-Amazon_EC2_NVMe_Instance_Storage_AWS1A2B3C4D5E6F7")
+        );
+        assert_eq!(disk_ids.by_id("/dev/nvme0n1p1"), None);
+        assert_eq!(disk_ids.by_id("/dev/nvme9n1"), None);
+
+        assert!(disk_ids.same_disk("/dev/nvme1n1", "/nvme1n1"));
+        assert!(disk_ids.same_disk("/dev/disk/by-id/wwn-0x5000c500a1b2c3d4", "/dev/nvme2n1"));
+        assert!(!disk_ids.same_disk("/dev/nvme1n1", "/dev/nvme2n1"));
+        assert!(disk_ids.same_disk("/dev/nvme9n1", "/nvme9n1"));
+        assert!(!disk_ids.same_disk("/dev/nvme9n1", "/dev/nvme8n1"));
+    }
+}
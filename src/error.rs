@@ -0,0 +1,298 @@
+//! Everything that can stop us from setting up the disks,
+//! and the exit code each one ends the process with.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Output;
+use std::time::Duration;
+
+use crate::catalog::{CatalogError, CatalogMismatch};
+use crate::detect::{DiskGroup, NvmeController, UnhealthyDisk};
+use crate::in_use::DeviceInUse;
+
+/// Arguments were invalid, or don't work together on this node.
+pub const EXIT_USAGE: i32 = 2;
+/// No suitable devices, or fewer than required, appeared.
+pub const EXIT_NO_DEVICES: i32 = 3;
+/// The devices don't match the disk catalog, with the `fail` policy.
+pub const EXIT_CATALOG_MISMATCH: i32 = 4;
+/// A command we ran failed, or couldn't be run at all.
+pub const EXIT_COMMAND_FAILED: i32 = 5;
+/// A command or file gave us output we don't understand.
+pub const EXIT_PARSE: i32 = 6;
+/// Reading or writing a file failed.
+pub const EXIT_IO: i32 = 7;
+/// The Kubernetes API failed.
+pub const EXIT_KUBE: i32 = 8;
+/// The devices differ in model, size or transport, with the `fail` policy.
+pub const EXIT_HETEROGENEOUS_DISKS: i32 = 9;
+/// Some devices are failing, with the `fail` health policy.
+pub const EXIT_UNHEALTHY_DISKS: i32 = 10;
+/// NVMe controllers don't have the required number of namespaces.
+pub const EXIT_NAMESPACE_LAYOUT: i32 = 11;
+/// Devices we were about to write to are held by something else.
+pub const EXIT_DEVICE_IN_USE: i32 = 12;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No suitable devices appeared before the timeout.
+    NoDevices {
+        waited: Duration,
+    },
+    /// Some suitable devices appeared, but fewer than the minimum.
+    NotEnoughDevices {
+        found: Vec<String>,
+        min_disks: usize,
+        waited: Duration,
+    },
+    /// The devices don't match what the catalog says this instance type has.
+    CatalogMismatch(Vec<CatalogMismatch>),
+    /// The devices differ in model, size or transport.
+    HeterogeneousDisks(Vec<DiskGroup>),
+    /// The SMART / Health logs of some devices say they're failing.
+    UnhealthyDisks(Vec<UnhealthyDisk>),
+    /// Some NVMe controllers don't have the required number of namespaces.
+    NamespaceLayout {
+        expected: usize,
+        controllers: Vec<NvmeController>,
+    },
+    /// Devices are held by other devices or processes.
+    DevicesInUse(Vec<DeviceInUse>),
+    /// A command exited unsuccessfully.
+    CommandFailed {
+        argv: Vec<String>,
+        code: Option<i32>,
+        stdout: String,
+        stderr: String,
+    },
+    /// A command couldn't be started, usually because it isn't installed.
+    CommandNotRun {
+        argv: Vec<String>,
+        source: std::io::Error,
+    },
+    /// Output of a command, or contents of a file, we couldn't make sense of.
+    ParseError {
+        what: String,
+        message: String,
+    },
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    KubeError {
+        context: String,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    InvalidCatalog(CatalogError),
+    /// We can't do what was asked on this node, like detecting its cloud provider.
+    Unsupported(String),
+}
+
+impl Error {
+    pub(crate) fn command_failed(argv: &[&str], output: &Output) -> Self {
+        Error::CommandFailed {
+            argv: argv.iter().map(|arg| arg.to_string()).collect(),
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+
+    pub(crate) fn io(path: impl Into<PathBuf>) -> impl FnOnce(std::io::Error) -> Self {
+        let path = path.into();
+        move |source| Error::Io { path, source }
+    }
+
+    pub(crate) fn parse(what: impl Into<String>) -> impl FnOnce(serde_json::Error) -> Self {
+        let what = what.into();
+        move |e| Error::ParseError {
+            what,
+            message: e.to_string(),
+        }
+    }
+
+    pub(crate) fn kube<E: std::error::Error + Send + Sync + 'static>(
+        context: impl Into<String>,
+    ) -> impl FnOnce(E) -> Self {
+        let context = context.into();
+        move |source| Error::KubeError {
+            context,
+            source: Box::new(source),
+        }
+    }
+
+    /// The process exit code for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::NoDevices { .. } | Error::NotEnoughDevices { .. } => EXIT_NO_DEVICES,
+            Error::CatalogMismatch(_) => EXIT_CATALOG_MISMATCH,
+            Error::HeterogeneousDisks(_) => EXIT_HETEROGENEOUS_DISKS,
+            Error::UnhealthyDisks(_) => EXIT_UNHEALTHY_DISKS,
+            Error::NamespaceLayout { .. } => EXIT_NAMESPACE_LAYOUT,
+            Error::DevicesInUse(_) => EXIT_DEVICE_IN_USE,
+            Error::CommandFailed { .. } | Error::CommandNotRun { .. } => EXIT_COMMAND_FAILED,
+            Error::ParseError { .. } => EXIT_PARSE,
+            Error::Io { .. } => EXIT_IO,
+            Error::KubeError { .. } => EXIT_KUBE,
+            Error::InvalidCatalog(_) | Error::Unsupported(_) => EXIT_USAGE,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoDevices { waited } => {
+                write!(f, "no suitable devices found after waiting {waited:?}")
+            }
+            Error::NotEnoughDevices {
+                found,
+                min_disks,
+                waited,
+            } => write!(
+                f,
+                "found {} suitable devices {found:?} after waiting {waited:?}, but at least {min_disks} are required",
+                found.len()
+            ),
+            Error::CatalogMismatch(mismatches) => {
+                write!(f, "devices don't match the disk catalog: ")?;
+                for (i, mismatch) in mismatches.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{mismatch}")?;
+                }
+                Ok(())
+            }
+            Error::HeterogeneousDisks(groups) => {
+                write!(f, "devices differ in model, size or transport: ")?;
+                for (i, group) in groups.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{group}")?;
+                }
+                Ok(())
+            }
+            Error::UnhealthyDisks(disks) => {
+                write!(f, "devices are unhealthy: ")?;
+                for (i, disk) in disks.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{disk}")?;
+                }
+                Ok(())
+            }
+            Error::NamespaceLayout {
+                expected,
+                controllers,
+            } => {
+                write!(
+                    f,
+                    "NVMe controllers should have {expected} namespaces each: "
+                )?;
+                for (i, controller) in controllers.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{controller}")?;
+                }
+                Ok(())
+            }
+            Error::DevicesInUse(devices) => {
+                write!(f, "devices are in use: ")?;
+                for (i, device) in devices.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{device}")?;
+                }
+                Ok(())
+            }
+            Error::CommandFailed {
+                argv,
+                code,
+                stdout,
+                stderr,
+            } => write!(
+                f,
+                "'{argv:?}' failed:
+Exit code: {code:?}
+Stdout:
+{stdout}
+Stderr:
+{stderr}"
+            ),
+            Error::CommandNotRun { argv, source } => {
+                write!(f, "failed to spawn '{argv:?}': {source}")
+            }
+            Error::ParseError { what, message } => {
+                write!(f, "failed to parse {what}: {message}")
+            }
+            Error::Io { path, source } => write!(f, "'{}': {source}", path.display()),
+            Error::KubeError { context, source } => write!(f, "{context}: {source}"),
+            Error::InvalidCatalog(e) => write!(f, "{e}"),
+            Error::Unsupported(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::CommandNotRun { source, .. } | Error::Io { source, .. } => Some(source),
+            Error::KubeError { source, .. } => Some(source.as_ref()),
+            Error::InvalidCatalog(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::error::{EXIT_COMMAND_FAILED, EXIT_NO_DEVICES, Error};
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_command_failed() {
+        let test_env = TestEnv::new();
+        test_env.mock_script("vgs", "echo 'some output'\necho 'no luck' >&2\nexit 5\n");
+        let err = test_env
+            .commander
+            .check_output(&["vgs", "--reportformat", "json"])
+            .unwrap_err();
+        let Error::CommandFailed {
+            argv,
+            code,
+            stdout,
+            stderr,
+        } = &err
+        else {
+            panic!("expected CommandFailed, got {err:?}");
+        };
+        assert_eq!(argv, &["vgs", "--reportformat", "json"]);
+        assert_eq!(*code, Some(5));
+        assert_eq!(stdout, "some output\n");
+        assert_eq!(stderr, "no luck\n");
+        assert_eq!(err.exit_code(), EXIT_COMMAND_FAILED);
+
+        let err = test_env
+            .commander
+            .check_output(&["ephemeral-storage-setup-not-installed"])
+            .unwrap_err();
+        assert!(matches!(err, Error::CommandNotRun { .. }), "{err:?}");
+        assert_eq!(err.exit_code(), EXIT_COMMAND_FAILED);
+
+        let err = Error::NoDevices {
+            waited: Duration::from_secs(60),
+        };
+        assert_eq!(err.exit_code(), EXIT_NO_DEVICES);
+        assert_eq!(
+            err.to_string(),
+            "no suitable devices found after waiting 60s"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Where the host's filesystem is, relative to us.
+//!
+//! We usually run in a container, which sees the host's root filesystem
+//! somewhere else: `/.bottlerocket/rootfs` in Bottlerocket bootstrap
+//! containers, or wherever a daemonset mounts it, usually `/host`.
+//! Every host path we read or write goes through a `HostRoot`.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use tracing::info;
+
+/// Where the host's root filesystem is in Bottlerocket bootstrap containers.
+pub const BOTTLEROCKET_ROOTFS_PATH: &str = "/.bottlerocket/rootfs";
+
+/// Where Bottlerocket bootstrap containers get their user-data.
+/// This is in the container's own filesystem, not the host's,
+/// and we read it before knowing the host root, so it isn't resolved through one.
+pub const BOTTLEROCKET_USER_DATA_PATH: &str =
+    "/.bottlerocket/bootstrap-containers/current/user-data";
+
+// Where our daemonset examples mount the host's root filesystem.
+const DAEMONSET_HOST_ROOT_PATH: &str = "/host";
+
+// How many links we follow, like the kernel's limit.
+const MAX_LINKS: usize = 40;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostRoot {
+    root: PathBuf,
+    // Whether device nodes have to be used from under the root,
+    // because our own /dev doesn't have them.
+    devices_under_root: bool,
+}
+
+impl Default for HostRoot {
+    /// Running directly on the host.
+    fn default() -> Self {
+        HostRoot::new("/")
+    }
+}
+
+impl HostRoot {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        // Daemonsets mount the host's /dev over ours, and LVM and
+        // /proc/swaps know devices by those names, so we prefer them.
+        // Bottlerocket bootstrap containers only have the host's devices
+        // under the root.
+        let dev_number = |path: &Path| std::fs::metadata(path).ok().map(|m| m.dev());
+        let devices_under_root = match dev_number(&root.join("dev")) {
+            Some(dev) => dev_number(Path::new("/dev")) != Some(dev),
+            None => false,
+        };
+        HostRoot {
+            root,
+            devices_under_root,
+        }
+    }
+
+    /// Finds the host's root filesystem: the Bottlerocket rootfs if it's
+    /// there, then `/host`, falling back to running directly on the host.
+    pub fn detect() -> Self {
+        let root = [BOTTLEROCKET_ROOTFS_PATH, DAEMONSET_HOST_ROOT_PATH]
+            .into_iter()
+            .find(|path| Path::new(path).is_dir())
+            .unwrap_or("/");
+        info!("Detected host root '{root}'");
+        HostRoot::new(root)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves an absolute path on the host to where we can see it.
+    pub fn path(&self, host_path: impl AsRef<Path>) -> PathBuf {
+        let host_path = host_path.as_ref();
+        self.root
+            .join(host_path.strip_prefix("/").unwrap_or(host_path))
+    }
+
+    /// Resolves a device path like `/dev/nvme1n1`, as lsblk reports it,
+    /// to one we can use.
+    pub fn device(&self, device: &str) -> String {
+        if self.devices_under_root {
+            self.path(device).to_string_lossy().into_owned()
+        } else {
+            device.to_owned()
+        }
+    }
+
+    /// Resolves a link on the host, like `/dev/disk/by-id/nvme-foo`,
+    /// to the host path it points to, like `/dev/nvme1n1`.
+    /// Absolute links point into the host root, not ours,
+    /// and the target only has to exist on the host.
+    pub(crate) fn resolve_link(&self, host_path: &str) -> io::Result<String> {
+        let mut path = PathBuf::from(host_path);
+        for hops in 0..MAX_LINKS {
+            match fs::read_link(self.path(&path)) {
+                Ok(target) => {
+                    let parent = path.parent().unwrap_or(Path::new("/"));
+                    path = normalize(&parent.join(target));
+                }
+                // Devices might only be in our own /dev.
+                Err(e)
+                    if e.kind() == io::ErrorKind::InvalidInput
+                        || (e.kind() == io::ErrorKind::NotFound && hops > 0) =>
+                {
+                    return Ok(path.to_string_lossy().into_owned());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::other("too many levels of links"))
+    }
+}
+
+/// Makes a path absolute without `.` or `..`, without following links.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normal.pop();
+            }
+            Component::Normal(name) => normal.push(name),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normal
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    use crate::host_root::HostRoot;
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_host_root() {
+        let host_root = HostRoot::default();
+        assert_eq!(host_root.path("/proc/swaps"), Path::new("/proc/swaps"));
+        assert_eq!(host_root.device("/dev/nvme1n1"), "/dev/nvme1n1");
+
+        // Like Bottlerocket, where the host's /dev is only under the root.
+        let test_env = TestEnv::new();
+        let rootfs = test_env.temp_dir.path().join("rootfs");
+        std::fs::create_dir_all(rootfs.join("dev")).unwrap();
+        let host_root = HostRoot::new(&rootfs);
+        assert_eq!(host_root.path("/proc/swaps"), rootfs.join("proc/swaps"));
+        assert_eq!(
+            host_root.path("etc/waagent.conf"),
+            rootfs.join("etc/waagent.conf")
+        );
+        assert_eq!(
+            host_root.device("/dev/nvme1n1"),
+            rootfs.join("dev/nvme1n1").to_str().unwrap()
+        );
+
+        // The host's /dev is the same as ours, so we keep using ours.
+        let host = test_env.temp_dir.path().join("host");
+        std::fs::create_dir(&host).unwrap();
+        std::os::unix::fs::symlink("/dev", host.join("dev")).unwrap();
+        let host_root = HostRoot::new(&host);
+        assert_eq!(host_root.device("/dev/nvme1n1"), "/dev/nvme1n1");
+        assert_eq!(
+            host_root.path("/var/lib/kubelet/config.yaml"),
+            host.join("var/lib/kubelet/config.yaml")
+        );
+
+        // Nor when the root doesn't have a /dev at all.
+        let host_root = HostRoot::new(test_env.temp_dir.path());
+        assert_eq!(host_root.device("/dev/nvme1n1"), "/dev/nvme1n1");
+    }
+
+    #[test]
+    fn test_resolve_link() {
+        let test_env = TestEnv::new();
+        let rootfs = test_env.temp_dir.path().join("rootfs");
+        let by_id = rootfs.join("dev/disk/by-id");
+        std::fs::create_dir_all(&by_id).unwrap();
+        let host_root = HostRoot::new(&rootfs);
+        // Like udev makes them, to devices that aren't under the root.
+        symlink("../../nvme1n1", by_id.join("nvme-foo")).unwrap();
+        assert_eq!(
+            host_root.resolve_link("/dev/disk/by-id/nvme-foo").unwrap(),
+            "/dev/nvme1n1"
+        );
+        // Absolute links, and links to links, stay under the root.
+        std::fs::write(rootfs.join("dev/nvme2n1"), "").unwrap();
+        symlink("/dev/nvme2n1", by_id.join("nvme-bar")).unwrap();
+        symlink("nvme-bar", by_id.join("nvme-baz")).unwrap();
+        assert_eq!(
+            host_root.resolve_link("/dev/disk/by-id/nvme-baz").unwrap(),
+            "/dev/nvme2n1"
+        );
+        // Paths that aren't links resolve to themselves.
+        assert_eq!(
+            host_root.resolve_link("/dev/nvme2n1").unwrap(),
+            "/dev/nvme2n1"
+        );
+        assert!(host_root.resolve_link("/dev/disk/by-id/missing").is_err());
+        symlink("loop", by_id.join("loop")).unwrap();
+        assert!(host_root.resolve_link("/dev/disk/by-id/loop").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+//! Whether something else is using a device, even with no children or
+//! mountpoints for lsblk to show: a device mapper or md device on top of it,
+//! swap, or a process holding it open exclusively.
+//!
+//! We check right before writing to devices, so `pvcreate -f` and `mkswap`
+//! never overwrite them.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::detect::sysfs::list_dir;
+use crate::error::Error;
+use crate::host_root::HostRoot;
+
+/// A device something else is using.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInUse {
+    pub device: String,
+    /// Devices or processes using it, as far as we can tell.
+    pub holders: Vec<String>,
+}
+
+impl fmt::Display for DeviceInUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.holders.is_empty() {
+            write!(
+                f,
+                "'{}' is open exclusively by an unknown holder",
+                self.device
+            )
+        } else {
+            write!(
+                f,
+                "'{}' is held by {}",
+                self.device,
+                self.holders.join(", ")
+            )
+        }
+    }
+}
+
+/// Fails with every device that's in use, if any are.
+pub(crate) fn check_not_in_use(host_root: &HostRoot, devices: &[String]) -> Result<(), Error> {
+    let swaps = swaps(host_root);
+    let mut in_use = vec![];
+    for device in devices {
+        if let Some(device_in_use) = device_in_use(host_root, &swaps, device)? {
+            in_use.push(device_in_use);
+        }
+    }
+    if in_use.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::DevicesInUse(in_use))
+    }
+}
+
+/// Checks a device, as we can open it, for holders,
+/// then tries to open it exclusively.
+fn device_in_use(
+    host_root: &HostRoot,
+    swaps: &[String],
+    device: &str,
+) -> Result<Option<DeviceInUse>, Error> {
+    let holders = holders(host_root, swaps, device);
+    if !holders.is_empty() {
+        return Ok(Some(DeviceInUse {
+            device: device.to_owned(),
+            holders,
+        }));
+    }
+    // Exclusive opens of block devices fail while the kernel or another
+    // process has claimed the device, or any of its partitions.
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_EXCL)
+        .open(device)
+    {
+        Ok(_) => Ok(None),
+        Err(e) if e.raw_os_error() == Some(libc::EBUSY) => Ok(Some(DeviceInUse {
+            device: device.to_owned(),
+            holders: processes_with_open(host_root, device),
+        })),
+        Err(e) => Err(Error::io(device)(e)),
+    }
+}
+
+/// Devices in `/sys/block/<dev>/holders`, and swap,
+/// for the device and its partitions.
+fn holders(host_root: &HostRoot, swaps: &[String], device: &str) -> Vec<String> {
+    let Some(name) = Path::new(device).file_name().and_then(|name| name.to_str()) else {
+        return vec![];
+    };
+    let dir = host_root.path(format!("/sys/block/{name}"));
+    let partitions = list_dir(&dir)
+        .into_iter()
+        .filter(|entry| entry.starts_with(name) && dir.join(entry).join("partition").exists());
+    let mut holders = vec![];
+    for dev in [name.to_owned()].into_iter().chain(partitions) {
+        if swaps.contains(&dev) {
+            holders.push(if dev == name {
+                "swap".to_owned()
+            } else {
+                format!("swap on {dev}")
+            });
+        }
+        let holders_dir = if dev == name {
+            dir.join("holders")
+        } else {
+            dir.join(&dev).join("holders")
+        };
+        for holder in list_dir(&holders_dir) {
+            // Device mapper devices have a more useful name, like vg-lv.
+            let dm_name = host_root.path(format!("/sys/block/{holder}/dm/name"));
+            let holder = match fs::read_to_string(dm_name) {
+                Ok(dm_name) => format!("{holder} ({})", dm_name.trim()),
+                Err(_) => holder,
+            };
+            holders.push(if dev == name {
+                holder
+            } else {
+                format!("{holder} on {dev}")
+            });
+        }
+    }
+    holders
+}
+
+/// Kernel names of the devices the host swaps to, from `/proc/swaps`.
+fn swaps(host_root: &HostRoot) -> Vec<String> {
+    let path = host_root.path("/proc/swaps");
+    let swaps = fs::read_to_string(&path)
+        .inspect_err(|e| debug!("Failed to read '{}': {e}", path.display()))
+        .unwrap_or_default();
+    // Filenames are sometimes missing the /dev at the beginning.
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|filename| Some(Path::new(filename).file_name()?.to_str()?.to_owned()))
+        .collect()
+}
+
+/// Processes on the host with the device open, like `process 1234 (mkfs.xfs)`.
+/// Seeing other processes' files needs the host PID namespace and privileges,
+/// so this is only ever a best effort.
+fn processes_with_open(host_root: &HostRoot, device: &str) -> Vec<String> {
+    let Ok(rdev) = fs::metadata(device).map(|metadata| metadata.rdev()) else {
+        return vec![];
+    };
+    let proc = host_root.path("/proc");
+    let mut processes = vec![];
+    for pid in list_dir(&proc) {
+        if !pid.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let fd_dir = proc.join(&pid).join("fd");
+        let has_open = list_dir(&fd_dir).iter().any(|fd| {
+            fs::metadata(fd_dir.join(fd)).is_ok_and(|metadata| {
+                metadata.file_type().is_block_device() && metadata.rdev() == rdev
+            })
+        });
+        if has_open {
+            let comm = fs::read_to_string(proc.join(&pid).join("comm")).unwrap_or_default();
+            processes.push(format!("process {pid} ({})", comm.trim()));
+        }
+    }
+    debug!("Processes with '{device}' open: {processes:?}");
+    processes
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::error::{EXIT_DEVICE_IN_USE, Error};
+    use crate::host_root::HostRoot;
+    use crate::in_use::{DeviceInUse, check_not_in_use};
+    use crate::test::TestEnv;
+
+    #[test]
+    fn test_check_not_in_use() {
+        let test_env = TestEnv::new();
+        let root = test_env.temp_dir.path().join("host");
+        let mkdir = |path: &str| fs::create_dir_all(root.join(path)).unwrap();
+        mkdir("sys/block/nvme1n1/holders/dm-0");
+        mkdir("sys/block/dm-0/dm");
+        fs::write(root.join("sys/block/dm-0/dm/name"), "scratch-data\n").unwrap();
+        mkdir("sys/block/nvme2n1/nvme2n1p1/holders/md127");
+        fs::write(root.join("sys/block/nvme2n1/nvme2n1p1/partition"), "1\n").unwrap();
+        mkdir("sys/block/nvme3n1/holders");
+        mkdir("proc");
+        fs::write(
+            root.join("proc/swaps"),
+            "Filename\tType\tSize\tUsed\tPriority\n\
+             /dev/nvme5n1\tpartition\t8388604\t0\t10\n",
+        )
+        .unwrap();
+        // Exclusive opens only mean something for block devices,
+        // so stand-ins for free devices are just files.
+        mkdir("dev");
+        let device = |name: &str| {
+            let path = root.join("dev").join(name);
+            fs::write(&path, "").unwrap();
+            path.display().to_string()
+        };
+        let host_root = HostRoot::new(&root);
+
+        let free = vec![device("nvme3n1"), device("nvme4n1")];
+        check_not_in_use(&host_root, &free).unwrap();
+
+        let devices = vec![
+            device("nvme1n1"),
+            device("nvme2n1"),
+            device("nvme3n1"),
+            device("nvme5n1"),
+        ];
+        let err = check_not_in_use(&host_root, &devices).unwrap_err();
+        let Error::DevicesInUse(in_use) = &err else {
+            panic!("expected DevicesInUse, got {err:?}");
+        };
+        assert_eq!(
+            in_use,
+            &[
+                DeviceInUse {
+                    device: devices[0].clone(),
+                    holders: vec!["dm-0 (scratch-data)".to_owned()],
+                },
+                DeviceInUse {
+                    device: devices[1].clone(),
+                    holders: vec!["md127 on nvme2n1p1".to_owned()],
+                },
+                DeviceInUse {
+                    device: devices[3].clone(),
+                    holders: vec!["swap".to_owned()],
+                },
+            ]
+        );
+        assert_eq!(err.exit_code(), EXIT_DEVICE_IN_USE);
+        assert_eq!(
+            in_use[0].to_string(),
+            format!("'{}' is held by dm-0 (scratch-data)", devices[0])
+        );
+
+        // Devices that aren't there can't be checked.
+        let missing = vec![root.join("dev/nvme9n1").display().to_string()];
+        let err = check_not_in_use(&host_root, &missing).unwrap_err();
+        assert!(matches!(err, Error::Io { .. }), "{err:?}");
+    }
+}
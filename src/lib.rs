@@ -4,17 +4,51 @@ use std::time::Duration;
 
 use clap::ValueEnum;
 
+use crate::error::Error;
+use crate::host_root::HostRoot;
+
+mod auto_detect;
+pub mod catalog;
 pub mod detect;
+mod disk_ids;
+pub mod error;
+pub mod host_root;
+pub mod in_use;
 pub mod lvm;
+pub mod metadata;
+mod nvme;
 mod remove_taint;
+pub mod selector;
 pub mod swap;
 
-#[derive(ValueEnum, Clone, Copy, Debug)]
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum CloudProvider {
     Aws,
     Gcp,
     Azure,
+    Oci,
+    Alibaba,
+    #[value(name = "openstack")]
+    OpenStack,
     Generic,
+    /// Detect the cloud provider from DMI data,
+    /// falling back to the node's provider ID.
+    Auto,
+}
+
+impl CloudProvider {
+    /// Resolves `Auto` to a concrete cloud provider.
+    /// Other variants are returned unchanged.
+    pub async fn resolve(
+        self,
+        node_name: Option<&str>,
+        host_root: &HostRoot,
+    ) -> Result<CloudProvider, Error> {
+        match self {
+            CloudProvider::Auto => auto_detect::detect_cloud_provider(node_name, host_root).await,
+            cloud_provider => Ok(cloud_provider),
+        }
+    }
 }
 
 #[derive(Clone, Default)]
@@ -25,45 +59,42 @@ pub struct Commander {
 }
 
 impl Commander {
-    fn check_output(&self, args: &[&str]) -> Output {
-        let failure_msg = format!("Failed to run '{args:?}'");
-        let output = self.unchecked_output(args);
-        let rc = output.status.code();
-        if rc.unwrap() != 0 {
-            panic!(
-                "{failure_msg}:
-Exit code: {rc:?}
-Stdout:
-{}
-Stderr:
-{}",
-                String::from_utf8_lossy(&output.stdout),
-                String::from_utf8_lossy(&output.stderr),
-            );
+    fn check_output(&self, args: &[&str]) -> Result<Output, Error> {
+        let output = self.unchecked_output(args)?;
+        if !output.status.success() {
+            return Err(Error::command_failed(args, &output));
         }
-        output
+        Ok(output)
     }
 
-    fn unchecked_output(&self, args: &[&str]) -> Output {
+    fn unchecked_output(&self, args: &[&str]) -> Result<Output, Error> {
         // We still check if we can even spawn the process,
         // we just don't check the return code.
-        let failure_msg = format!("Failed to spawn '{args:?}'");
+        self.try_output(args)
+            .map_err(|source| Error::CommandNotRun {
+                argv: args.iter().map(|arg| arg.to_string()).collect(),
+                source,
+            })
+    }
+
+    /// Runs a command that may not be installed.
+    fn try_output(&self, args: &[&str]) -> std::io::Result<Output> {
         Command::new(args[0])
             .args(&args[1..])
             .envs(&self.envs)
             .output()
-            .expect(&failure_msg)
     }
 }
 
-pub async fn load_kube_config() -> kube::Config {
-    let mut config = kube::Config::incluster().unwrap();
+pub async fn load_kube_config() -> Result<kube::Config, Error> {
+    let mut config =
+        kube::Config::incluster().map_err(Error::kube("failed to load in-cluster config"))?;
 
     config.connect_timeout = Some(Duration::from_secs(30));
     config.read_timeout = Some(Duration::from_secs(30));
     config.write_timeout = Some(Duration::from_secs(30));
 
-    config
+    Ok(config)
 }
 
 #[cfg(test)]
@@ -76,6 +107,7 @@ mod test {
     use tempfile::TempDir;
 
     use crate::Commander;
+    use crate::host_root::HostRoot;
 
     pub(crate) struct TestEnv {
         pub(crate) temp_dir: TempDir,
@@ -92,13 +124,30 @@ mod test {
             commander
                 .envs
                 .insert("NODE_NAME".to_owned(), "test-node".to_owned());
-            TestEnv {
+            let test_env = TestEnv {
                 temp_dir,
                 commander,
-            }
+            };
+            // Never wait on the real udev.
+            test_env.mock("udevadm", 0, "");
+            test_env
         }
 
         pub(crate) fn mock(&self, command: &str, exit_code: u8, output: &str) {
+            self.mock_script(
+                command,
+                &format!(
+                    "cat <<'EOF'
+{output}
+EOF
+exit {exit_code}
+"
+                ),
+            );
+        }
+
+        /// Mocks a command with an arbitrary bash script.
+        pub(crate) fn mock_script(&self, command: &str, script: &str) {
             let mut file = OpenOptions::new()
                 .write(true)
                 .truncate(true)
@@ -110,17 +159,20 @@ mod test {
                 format!(
                     "#!/bin/bash
 set -euo pipefail
-cat <<'EOF'
-{output}
-EOF
-exit {exit_code}
-"
+{script}"
                 )
                 .as_bytes(),
             )
             .unwrap();
         }
 
+        /// An empty host root, for tests that don't set up the host's state.
+        pub(crate) fn host_root(&self) -> HostRoot {
+            let path = self.temp_dir.path().join("host");
+            std::fs::create_dir_all(&path).unwrap();
+            HostRoot::new(path)
+        }
+
         /// Reads test data file at path (relative to the root of the repo).
         pub(crate) fn read_testdata(&self, path: &str) -> String {
             std::fs::read_to_string(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap()
@@ -1,8 +1,12 @@
 use serde::Deserialize;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::Commander;
-use crate::detect::DiskDetectorTrait;
+use crate::detect::{DiskDetectorTrait, DiskInfo};
+use crate::disk_ids::DiskIds;
+use crate::error::Error;
+use crate::host_root::HostRoot;
+use crate::in_use::check_not_in_use;
 use crate::remove_taint::remove_taint;
 
 #[derive(Deserialize)]
@@ -29,6 +33,7 @@ struct PvReport {
 pub struct LvmController<D: DiskDetectorTrait> {
     pub commander: Commander,
     pub disk_detector: D,
+    pub host_root: HostRoot,
     pub node_name: Option<String>,
     pub taint_key: String,
     pub remove_taint: bool,
@@ -36,18 +41,35 @@ pub struct LvmController<D: DiskDetectorTrait> {
 }
 
 impl<D: DiskDetectorTrait> LvmController<D> {
-    pub async fn setup(&self) {
+    pub async fn setup(&self) -> Result<(), Error> {
         info!("Starting NVMe disk configuration with LVM...");
-        if self.volume_group_exists() {
+        if self.volume_group_exists()? {
             info!("Volume group {} already exists.", self.vg_name);
         } else {
-            let devices = self.disk_detector.detect_devices();
-            for device in &devices {
-                if !self.physical_volume_exists(device) {
-                    self.pvcreate(device);
+            let groups = self.disk_detector.detect_device_groups()?;
+            if groups.len() > 1 {
+                warn!(
+                    "Creating volume group {} from {} groups of disks that differ in model, size or transport",
+                    self.vg_name,
+                    groups.len()
+                );
+            }
+            let disks: Vec<DiskInfo> = groups.into_iter().flat_map(|group| group.devices).collect();
+            // Disks to reclaim are checked once the host gives them up.
+            let paths: Vec<String> = disks
+                .iter()
+                .filter(|disk| !disk.reclaim)
+                .map(|disk| disk.path.clone())
+                .collect();
+            check_not_in_use(&self.host_root, &paths)?;
+            self.disk_detector.reclaim(&disks)?;
+            let disk_ids = DiskIds::read(&self.host_root);
+            for disk in &disks {
+                if !self.physical_volume_exists(&disk_ids, disk)? {
+                    self.pvcreate(disk)?;
                 }
             }
-            self.vgcreate(&devices);
+            self.vgcreate(&disks)?;
         }
         info!("LVM setup completed successfully");
         if self.remove_taint {
@@ -55,49 +77,60 @@ impl<D: DiskDetectorTrait> LvmController<D> {
                 self.node_name.as_ref().expect("clap enforced"),
                 &self.taint_key,
             )
-            .await;
+            .await?;
         }
+        Ok(())
+    }
+
+    /// Runs an LVM reporting command, returning its first report.
+    fn report(&self, args: &[&str]) -> Result<LvmReport, Error> {
+        let output = self.commander.check_output(args)?;
+        let what = format!("output of '{}'", args.join(" "));
+        let wrapper: LvmReportWrapper =
+            serde_json::from_slice(&output.stdout).map_err(Error::parse(&what))?;
+        wrapper
+            .report
+            .into_iter()
+            .next()
+            .ok_or_else(|| Error::ParseError {
+                what,
+                message: "no report".to_owned(),
+            })
     }
 
-    fn volume_group_exists(&self) -> bool {
-        let vgs_report = self
-            .commander
-            .check_output(&["vgs", "--reportformat", "json"]);
-        let vgs_report: LvmReportWrapper = serde_json::from_slice(&vgs_report.stdout)
-            .expect("Failed to deserialize output of 'vgs --reportformat json'");
-        vgs_report.report[0]
+    fn volume_group_exists(&self) -> Result<bool, Error> {
+        let report = self.report(&["vgs", "--reportformat", "json"])?;
+        Ok(report
             .vg
-            .as_ref()
-            .unwrap()
+            .unwrap_or_default()
             .iter()
-            .any(|vg| vg.vg_name == self.vg_name)
+            .any(|vg| vg.vg_name == self.vg_name))
     }
 
-    fn physical_volume_exists(&self, device: &str) -> bool {
-        let pvs_report = self
-            .commander
-            .check_output(&["pvs", "--reportformat", "json"]);
-        let pvs_report: LvmReportWrapper = serde_json::from_slice(&pvs_report.stdout)
-            .expect("Failed to deserialize output of 'pvs --reportformat json'");
-        pvs_report.report[0]
+    fn physical_volume_exists(&self, disk_ids: &DiskIds, disk: &DiskInfo) -> Result<bool, Error> {
+        let report = self.report(&["pvs", "--reportformat", "json"])?;
+        // LVM may name the physical volume by any of the disk's links.
+        Ok(report
             .pv
-            .as_ref()
-            .unwrap()
+            .unwrap_or_default()
             .iter()
-            .any(|pv| pv.pv_name == device)
+            .any(|pv| disk_ids.same_disk(&pv.pv_name, &disk.path)))
     }
 
-    fn pvcreate(&self, device: &str) {
-        info!("Creating physical volume on {device}");
-        self.commander.check_output(&["pvcreate", "-f", device]);
+    fn pvcreate(&self, disk: &DiskInfo) -> Result<(), Error> {
+        info!("Creating physical volume on {disk}");
+        self.commander
+            .check_output(&["pvcreate", "-f", disk.stable_path()])?;
+        Ok(())
     }
 
-    fn vgcreate(&self, devices: &[String]) {
+    fn vgcreate(&self, disks: &[DiskInfo]) -> Result<(), Error> {
         info!("Creating volume group {}", &self.vg_name);
-        let mut args = Vec::with_capacity(devices.len() + 2);
+        let mut args = Vec::with_capacity(disks.len() + 2);
         args.push("vgcreate");
         args.push(&self.vg_name);
-        args.extend(devices.iter().map(|d| d.as_str()));
-        self.commander.check_output(&args);
+        args.extend(disks.iter().map(|disk| disk.stable_path()));
+        self.commander.check_output(&args)?;
+        Ok(())
     }
 }
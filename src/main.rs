@@ -1,16 +1,25 @@
 use std::io::ErrorKind;
+use std::path::PathBuf;
 use std::process::exit;
 use std::thread::sleep;
 use std::time::Duration;
 
 use clap::{CommandFactory, Parser, Subcommand};
 
-use ephemeral_storage_setup::detect::DiskDetector;
+use ephemeral_storage_setup::catalog::{Catalog, CatalogPolicy};
+use ephemeral_storage_setup::detect::{
+    BlockDeviceSource, DetectOptions, DiskDetector, DiskGroupPolicy, HealthPolicy, NamespacePolicy,
+};
+use ephemeral_storage_setup::error::Error;
+use ephemeral_storage_setup::host_root::{BOTTLEROCKET_USER_DATA_PATH, HostRoot};
 use ephemeral_storage_setup::lvm::LvmController;
+use ephemeral_storage_setup::metadata::fetch_instance_metadata;
+use ephemeral_storage_setup::selector::Selector;
 use ephemeral_storage_setup::swap::SwapController;
 use ephemeral_storage_setup::{CloudProvider, Commander};
-use tracing::info;
+use tokio::runtime::Runtime;
 use tracing::level_filters::LevelFilter;
+use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
 
 #[derive(Parser)]
@@ -73,6 +82,17 @@ enum Commands {
         /// Higher values will cause kswapd to swap more and earlier.
         #[arg(long, env, default_value_t = 100)]
         vm_watermark_scale_factor: usize,
+
+        /// Give each disk group its own swap priority, so the preferred group
+        /// fills up first, instead of spreading pages across all the disks.
+        #[clap(long, env)]
+        swap_group_priorities: bool,
+    },
+    /// Only detect devices, printing a JSON inventory of every block device,
+    /// whether it would be used, and why not, without changing anything.
+    Detect {
+        #[clap(flatten)]
+        common_args: CommonArgs,
     },
     /// Don't do anything, just sleep.
     /// This allows us to not need a separate image just to keep
@@ -100,6 +120,202 @@ struct CommonArgs {
 
     #[clap(long, env, requires_if("true", "node_name"))]
     remove_taint: bool,
+
+    /// Unmount and wipe the OpenStack ephemeral disk if cloud-init
+    /// has already mounted it, so it can be used.
+    #[clap(long, env)]
+    openstack_reclaim_ephemeral: bool,
+
+    /// Only use devices matching this expression,
+    /// for example 'model=~"SAMSUNG.*" && size>=1TiB && tran in (nvme,sas)'.
+    ///
+    /// Supported fields are model, serial, wwn, size, rota, tran and vendor.
+    /// With the generic cloud provider, this replaces the check
+    /// that devices are connected by NVMe.
+    #[clap(long, env)]
+    select: Option<Selector>,
+
+    /// Where to get the list of block devices from.
+    #[clap(long, env, value_enum, default_value_t)]
+    block_device_source: BlockDeviceSource,
+
+    /// Identify AWS instance store volumes by sending an NVMe Identify
+    /// Controller command to each disk, rather than by their model string.
+    #[clap(long, env)]
+    aws_nvme_identify: bool,
+
+    /// Comma separated transports devices may be connected by,
+    /// as reported by lsblk, overriding the cloud provider's default.
+    /// Use 'none' for devices lsblk reports without a transport,
+    /// such as virtio-scsi disks.
+    #[clap(long, env, value_delimiter = ',')]
+    transports: Option<Vec<String>>,
+
+    /// On Azure VM sizes without NVMe local disks, unmount the temporary
+    /// resource disk on the host and disable it in the waagent config,
+    /// so it can be used instead.
+    #[clap(long, env)]
+    azure_reclaim_resource_disk: bool,
+
+    /// Wait for this many devices to appear before configuring them.
+    /// If fewer appear before the timeout, we continue with what we found,
+    /// as long as there are at least --min-disks of them.
+    #[clap(long, env)]
+    expected_disks: Option<usize>,
+
+    /// Fail if fewer than this many devices appear before the timeout.
+    #[clap(long, env, default_value_t = 1)]
+    min_disks: usize,
+
+    /// How long to wait for devices to appear, for example '90s' or '5m'.
+    #[clap(long, env, default_value = "60s", value_parser = parse_duration)]
+    device_wait_timeout: Duration,
+
+    /// Scheme and host of the cloud provider's instance metadata service,
+    /// replacing the default, for example 'http://127.0.0.1:8080'.
+    #[clap(long, env)]
+    metadata_endpoint: Option<String>,
+
+    /// How long to wait for each request to the instance metadata service.
+    /// If it doesn't respond, we continue without instance metadata.
+    #[clap(long, env, default_value = "2s", value_parser = parse_duration)]
+    metadata_timeout: Duration,
+
+    /// YAML file of expected local disks per instance type,
+    /// adding to or replacing entries in the built-in catalog.
+    #[clap(long, env)]
+    disk_catalog: Option<PathBuf>,
+
+    /// What to do when the detected devices don't match the disk catalog
+    /// for the instance type reported by the metadata service.
+    #[clap(long, env, value_enum, default_value_t)]
+    disk_catalog_policy: CatalogPolicy,
+
+    /// What to do when the detected devices differ in model, size or transport.
+    #[clap(long, env, value_enum, default_value_t)]
+    disk_group_policy: DiskGroupPolicy,
+
+    /// What to do when a detected NVMe device's SMART / Health log
+    /// reports critical warnings, too little spare capacity,
+    /// used up endurance or media errors.
+    #[clap(long, env, value_enum, default_value_t)]
+    disk_health_policy: HealthPolicy,
+
+    /// Which namespaces to use when an NVMe controller exposes more than one:
+    /// all of them, only the first, or all of them as long as every
+    /// controller has exactly --nvme-namespaces.
+    #[clap(
+        long,
+        env,
+        value_enum,
+        default_value_t,
+        requires_if("require", "nvme_namespaces")
+    )]
+    nvme_namespace_policy: NamespacePolicy,
+
+    /// How many namespaces each NVMe controller must have,
+    /// with the require namespace policy.
+    #[clap(long, env)]
+    nvme_namespaces: Option<usize>,
+
+    /// Only use detected devices matching this path or glob,
+    /// like '/dev/nvme1n1' or '/dev/disk/by-id/nvme-SAMSUNG*'.
+    /// Links under /dev/disk are resolved to the devices they point to.
+    /// May be repeated, or comma separated.
+    #[clap(long, env, value_delimiter = ',')]
+    include_device: Vec<String>,
+
+    /// Never use detected devices matching this path or glob,
+    /// like '/dev/disk/by-path/pci-0000:00:1e.0-nvme-1'.
+    /// May be repeated, or comma separated.
+    #[clap(long, env, value_delimiter = ',')]
+    exclude_device: Vec<String>,
+
+    /// Where the host's root filesystem is mounted in this container.
+    /// Host devices, sysfs, /proc/swaps and config files are all read
+    /// from under it.
+    ///
+    /// If not provided, we use the Bottlerocket rootfs if it exists,
+    /// then /host, then /.
+    #[clap(long, env)]
+    host_root: Option<PathBuf>,
+}
+
+/// Parses a duration in seconds, with an optional 's', 'm' or 'h' suffix.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{value}'"))?;
+    let seconds = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 60 * 60,
+        _ => return Err(format!("invalid duration unit '{unit}' in '{value}'")),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+impl CommonArgs {
+    fn host_root(&self) -> HostRoot {
+        match &self.host_root {
+            Some(root) => HostRoot::new(root),
+            None => HostRoot::detect(),
+        }
+    }
+
+    /// Resolves the cloud provider and builds a disk detector for it.
+    fn disk_detector(
+        &self,
+        commander: &Commander,
+        runtime: &Runtime,
+        host_root: &HostRoot,
+    ) -> Result<(CloudProvider, DiskDetector), Error> {
+        let cloud_provider = runtime.block_on(
+            self.cloud_provider
+                .resolve(self.node_name.as_deref(), host_root),
+        )?;
+        let instance_metadata = runtime.block_on(fetch_instance_metadata(
+            cloud_provider,
+            self.metadata_endpoint.as_deref(),
+            self.metadata_timeout,
+        ));
+        let catalog = match &self.disk_catalog {
+            Some(path) => Catalog::embedded()
+                .with_overrides(path)
+                .map_err(Error::InvalidCatalog)?,
+            None => Catalog::embedded(),
+        };
+        let options = DetectOptions {
+            openstack_reclaim_ephemeral: self.openstack_reclaim_ephemeral,
+            selector: self.select.clone(),
+            block_device_source: self.block_device_source,
+            aws_nvme_identify: self.aws_nvme_identify,
+            transports: self.transports.clone(),
+            azure_reclaim_resource_disk: self.azure_reclaim_resource_disk,
+            expected_disks: self.expected_disks,
+            min_disks: Some(self.min_disks),
+            device_wait_timeout: self.device_wait_timeout,
+            instance_metadata,
+            catalog,
+            catalog_policy: self.disk_catalog_policy,
+            disk_group_policy: self.disk_group_policy,
+            health_policy: self.disk_health_policy,
+            namespace_policy: self.nvme_namespace_policy,
+            namespaces_per_controller: self.nvme_namespaces,
+            include_devices: self.include_device.clone(),
+            exclude_devices: self.exclude_device.clone(),
+            host_root: host_root.clone(),
+        };
+        let disk_detector = DiskDetector::builder(cloud_provider)
+            .commander(commander.clone())
+            .options(options)
+            .build()?;
+        Ok((cloud_provider, disk_detector))
+    }
 }
 
 fn print_help_and_exit() -> ! {
@@ -115,11 +331,13 @@ fn main() {
                 .with_default_directive(LevelFilter::DEBUG.into())
                 .from_env_lossy(),
         )
+        // Keep stdout for the output of the detect command.
+        .with_writer(std::io::stderr)
         .init();
     let command = args.command.unwrap_or_else(|| {
         // If they didn't pass a command, try to detect if we're a bottlerocket
         // bootstrap container with the args in user-data.
-        let userdata_path = "/.bottlerocket/bootstrap-containers/current/user-data";
+        let userdata_path = BOTTLEROCKET_USER_DATA_PATH;
         match std::fs::read_to_string(userdata_path) {
             Ok(userdata) => {
                 info!("Found userdata at '{userdata_path}'");
@@ -140,72 +358,89 @@ fn main() {
             Err(e) => panic!("{e:?}"),
         }
     });
+    if let Err(e) = run(command) {
+        error!("{e}");
+        exit(e.exit_code());
+    }
+}
+
+fn run(command: Commands) -> Result<(), Error> {
     let commander = Commander::default();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
     match command {
         Commands::Lvm {
-            common_args:
-                CommonArgs {
-                    cloud_provider,
-                    node_name,
-                    taint_key,
-                    remove_taint,
-                },
+            common_args,
             vg_name,
         } => {
-            let disk_detector = DiskDetector::new(commander.clone(), cloud_provider);
-            tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(
-                    LvmController {
-                        commander,
-                        disk_detector,
-                        node_name,
-                        taint_key,
-                        remove_taint,
-                        vg_name,
-                    }
-                    .setup(),
-                )
-        }
-        Commands::Swap {
-            common_args:
-                CommonArgs {
-                    cloud_provider,
+            let host_root = common_args.host_root();
+            let (_, disk_detector) = common_args.disk_detector(&commander, &runtime, &host_root)?;
+            let CommonArgs {
+                node_name,
+                taint_key,
+                remove_taint,
+                ..
+            } = common_args;
+            runtime.block_on(
+                LvmController {
+                    commander,
+                    disk_detector,
+                    host_root,
                     node_name,
                     taint_key,
                     remove_taint,
-                },
+                    vg_name,
+                }
+                .setup(),
+            )
+        }
+        Commands::Swap {
+            common_args,
             bottlerocket_enable_swap,
             hack_restart_kubelet_enable_swap,
             apply_sysctls,
             vm_swappiness,
             vm_min_free_kbytes,
             vm_watermark_scale_factor,
+            swap_group_priorities,
         } => {
-            let disk_detector = DiskDetector::new(commander.clone(), cloud_provider);
-            tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(
-                    SwapController {
-                        cloud_provider,
-                        commander,
-                        disk_detector,
-                        node_name,
-                        taint_key,
-                        remove_taint,
-                        bottlerocket_enable_swap,
-                        hack_restart_kubelet_enable_swap,
-                        apply_sysctls,
-                        vm_swappiness,
-                        vm_min_free_kbytes,
-                        vm_watermark_scale_factor,
-                    }
-                    .setup(),
-                )
+            let host_root = common_args.host_root();
+            let (cloud_provider, disk_detector) =
+                common_args.disk_detector(&commander, &runtime, &host_root)?;
+            let CommonArgs {
+                node_name,
+                taint_key,
+                remove_taint,
+                ..
+            } = common_args;
+            runtime.block_on(
+                SwapController {
+                    cloud_provider,
+                    commander,
+                    disk_detector,
+                    host_root,
+                    node_name,
+                    taint_key,
+                    remove_taint,
+                    bottlerocket_enable_swap,
+                    hack_restart_kubelet_enable_swap,
+                    apply_sysctls,
+                    vm_swappiness,
+                    vm_min_free_kbytes,
+                    vm_watermark_scale_factor,
+                    swap_group_priorities,
+                }
+                .setup(),
+            )
+        }
+        Commands::Detect { common_args } => {
+            let host_root = common_args.host_root();
+            let (_, disk_detector) = common_args.disk_detector(&commander, &runtime, &host_root)?;
+            let inventory = disk_detector.inventory()?;
+            println!("{}", serde_json::to_string_pretty(&inventory).unwrap());
+            Ok(())
         }
         Commands::Sleep => loop {
             sleep(Duration::from_secs(3600));
@@ -0,0 +1,537 @@
+//! What instance we're running on, from the cloud provider's metadata service.
+//!
+//! Metadata is only ever informational. If the service is unreachable,
+//! we log it and carry on without it.
+
+use std::fmt;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Method, Request, StatusCode};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::CloudProvider;
+
+const AWS_IMDS_ENDPOINT: &str = "http://169.254.169.254";
+// We only need the token for the few requests we make right away.
+const AWS_IMDS_TOKEN_TTL_SECONDS: &str = "60";
+const GCP_METADATA_ENDPOINT: &str = "http://metadata.google.internal";
+const AZURE_IMDS_ENDPOINT: &str = "http://169.254.169.254";
+const AZURE_IMDS_API_VERSION: &str = "2021-02-01";
+
+/// What we know about the instance we're running on.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InstanceMetadata {
+    pub instance_id: String,
+    /// Instance type, machine type, or VM size, depending on the cloud provider.
+    /// ie: `i4i.xlarge`, `n2-standard-8`, or `Standard_L8s_v3`.
+    pub instance_type: String,
+    /// Availability zone, ie: `us-east-1a` or `us-central1-a`.
+    /// Azure VMs outside of availability zones just have their region.
+    pub zone: String,
+}
+
+#[derive(Debug)]
+pub enum MetadataError {
+    Timeout { url: String, timeout: Duration },
+    Request { url: String, error: String },
+    Status { url: String, status: StatusCode },
+    Parse { url: String, error: String },
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::Timeout { url, timeout } => {
+                write!(f, "request to '{url}' timed out after {timeout:?}")
+            }
+            MetadataError::Request { url, error } => {
+                write!(f, "request to '{url}' failed: {error}")
+            }
+            MetadataError::Status { url, status } => {
+                write!(f, "request to '{url}' returned {status}")
+            }
+            MetadataError::Parse { url, error } => {
+                write!(f, "failed to parse response from '{url}': {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+/// A cloud provider's metadata service.
+pub trait MetadataClient {
+    fn instance_metadata(
+        &self,
+    ) -> impl Future<Output = Result<InstanceMetadata, MetadataError>> + Send;
+}
+
+/// Fetches metadata for the instance we're running on, logging it.
+/// Returns `None` if the cloud provider has no metadata backend,
+/// or the metadata service couldn't be reached.
+///
+/// `endpoint` replaces the scheme and host of the metadata service,
+/// ie: `http://127.0.0.1:8080`.
+pub async fn fetch_instance_metadata(
+    cloud_provider: CloudProvider,
+    endpoint: Option<&str>,
+    timeout: Duration,
+) -> Option<InstanceMetadata> {
+    let result = match cloud_provider {
+        CloudProvider::Aws => AwsImds::new(endpoint, timeout).instance_metadata().await,
+        CloudProvider::Gcp => {
+            GcpMetadata::new(endpoint, timeout)
+                .instance_metadata()
+                .await
+        }
+        CloudProvider::Azure => AzureImds::new(endpoint, timeout).instance_metadata().await,
+        _ => {
+            debug!("No instance metadata backend for cloud provider {cloud_provider:?}");
+            return None;
+        }
+    };
+    match result {
+        Ok(metadata) => {
+            info!(
+                "Running on instance '{}' of type '{}' in zone '{}'",
+                metadata.instance_id, metadata.instance_type, metadata.zone
+            );
+            Some(metadata)
+        }
+        Err(e) => {
+            warn!("Failed to fetch instance metadata, continuing without it: {e}");
+            None
+        }
+    }
+}
+
+/// AWS instance metadata service, using IMDSv2 session tokens.
+pub struct AwsImds {
+    http: HttpClient,
+    endpoint: String,
+}
+
+impl AwsImds {
+    pub fn new(endpoint: Option<&str>, timeout: Duration) -> Self {
+        AwsImds {
+            http: HttpClient::new(timeout),
+            endpoint: endpoint.unwrap_or(AWS_IMDS_ENDPOINT).to_owned(),
+        }
+    }
+
+    async fn get(&self, token: &str, path: &str) -> Result<String, MetadataError> {
+        self.http
+            .request(
+                Method::GET,
+                &format!("{}/latest/meta-data/{path}", self.endpoint),
+                &[("X-aws-ec2-metadata-token", token)],
+            )
+            .await
+    }
+}
+
+impl MetadataClient for AwsImds {
+    async fn instance_metadata(&self) -> Result<InstanceMetadata, MetadataError> {
+        // Pods without host networking only get a response to this
+        // if the instance allows a hop limit of at least 2.
+        let token = self
+            .http
+            .request(
+                Method::PUT,
+                &format!("{}/latest/api/token", self.endpoint),
+                &[(
+                    "X-aws-ec2-metadata-token-ttl-seconds",
+                    AWS_IMDS_TOKEN_TTL_SECONDS,
+                )],
+            )
+            .await?;
+        Ok(InstanceMetadata {
+            instance_id: self.get(&token, "instance-id").await?,
+            instance_type: self.get(&token, "instance-type").await?,
+            zone: self.get(&token, "placement/availability-zone").await?,
+        })
+    }
+}
+
+/// GCP metadata server.
+pub struct GcpMetadata {
+    http: HttpClient,
+    endpoint: String,
+}
+
+impl GcpMetadata {
+    pub fn new(endpoint: Option<&str>, timeout: Duration) -> Self {
+        GcpMetadata {
+            http: HttpClient::new(timeout),
+            endpoint: endpoint.unwrap_or(GCP_METADATA_ENDPOINT).to_owned(),
+        }
+    }
+
+    async fn get(&self, path: &str) -> Result<String, MetadataError> {
+        self.http
+            .request(
+                Method::GET,
+                &format!("{}/computeMetadata/v1/instance/{path}", self.endpoint),
+                &[("Metadata-Flavor", "Google")],
+            )
+            .await
+    }
+}
+
+impl MetadataClient for GcpMetadata {
+    async fn instance_metadata(&self) -> Result<InstanceMetadata, MetadataError> {
+        // The machine type and zone are full resource names, like
+        // projects/123456789/machineTypes/n2-standard-8.
+        let last_segment = |name: String| match name.rsplit_once('/') {
+            Some((_, last)) => last.to_owned(),
+            None => name,
+        };
+        Ok(InstanceMetadata {
+            instance_id: self.get("id").await?,
+            instance_type: last_segment(self.get("machine-type").await?),
+            zone: last_segment(self.get("zone").await?),
+        })
+    }
+}
+
+/// Azure instance metadata service.
+pub struct AzureImds {
+    http: HttpClient,
+    endpoint: String,
+}
+
+impl AzureImds {
+    pub fn new(endpoint: Option<&str>, timeout: Duration) -> Self {
+        AzureImds {
+            http: HttpClient::new(timeout),
+            endpoint: endpoint.unwrap_or(AZURE_IMDS_ENDPOINT).to_owned(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureCompute {
+    vm_id: String,
+    vm_size: String,
+    location: String,
+    #[serde(default)]
+    zone: String,
+}
+
+impl MetadataClient for AzureImds {
+    async fn instance_metadata(&self) -> Result<InstanceMetadata, MetadataError> {
+        let url = format!(
+            "{}/metadata/instance/compute?api-version={AZURE_IMDS_API_VERSION}",
+            self.endpoint
+        );
+        let body = self
+            .http
+            .request(Method::GET, &url, &[("Metadata", "true")])
+            .await?;
+        let compute: AzureCompute =
+            serde_json::from_str(&body).map_err(|e| MetadataError::Parse {
+                url: url.clone(),
+                error: e.to_string(),
+            })?;
+        Ok(InstanceMetadata {
+            instance_id: compute.vm_id,
+            instance_type: compute.vm_size,
+            zone: if compute.zone.is_empty() {
+                compute.location
+            } else {
+                format!("{}-{}", compute.location, compute.zone)
+            },
+        })
+    }
+}
+
+struct HttpClient {
+    client: Client<HttpConnector, Full<Bytes>>,
+    timeout: Duration,
+}
+
+impl HttpClient {
+    fn new(timeout: Duration) -> Self {
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(Some(timeout));
+        HttpClient {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            timeout,
+        }
+    }
+
+    /// Makes a request with an empty body, returning the response body.
+    async fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<String, MetadataError> {
+        let mut request = Request::builder().method(method).uri(url);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request
+            .body(Full::default())
+            .map_err(|e| MetadataError::Request {
+                url: url.to_owned(),
+                error: e.to_string(),
+            })?;
+        let response = async {
+            let response =
+                self.client
+                    .request(request)
+                    .await
+                    .map_err(|e| MetadataError::Request {
+                        url: url.to_owned(),
+                        error: e.to_string(),
+                    })?;
+            let status = response.status();
+            let body = response
+                .into_body()
+                .collect()
+                .await
+                .map_err(|e| MetadataError::Request {
+                    url: url.to_owned(),
+                    error: e.to_string(),
+                })?
+                .to_bytes();
+            if !status.is_success() {
+                return Err(MetadataError::Status {
+                    url: url.to_owned(),
+                    status,
+                });
+            }
+            String::from_utf8(body.to_vec())
+                .map(|body| body.trim().to_owned())
+                .map_err(|e| MetadataError::Parse {
+                    url: url.to_owned(),
+                    error: e.to_string(),
+                })
+        };
+        tokio::time::timeout(self.timeout, response)
+            .await
+            .map_err(|_| MetadataError::Timeout {
+                url: url.to_owned(),
+                timeout: self.timeout,
+            })?
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use crate::CloudProvider;
+    use crate::metadata::{
+        AwsImds, AzureImds, GcpMetadata, InstanceMetadata, MetadataClient, MetadataError,
+        fetch_instance_metadata,
+    };
+    use crate::test::TestEnv;
+
+    /// A request as the stand-in server saw it.
+    struct Request {
+        method: String,
+        path: String,
+        headers: Vec<(String, String)>,
+    }
+
+    impl Request {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// Starts a local HTTP stand-in for a metadata service,
+    /// returning its endpoint.
+    /// `respond` returns the status code and body for each request.
+    fn serve(respond: impl Fn(&Request) -> (u16, String) + Send + 'static) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap_or_default().to_owned();
+                let path = parts.next().unwrap_or_default().to_owned();
+                let mut headers = vec![];
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((name, value)) => headers.push((name.to_owned(), value.to_owned())),
+                        None => break,
+                    }
+                }
+                let (status, body) = respond(&Request {
+                    method,
+                    path,
+                    headers,
+                });
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Whatever\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        endpoint
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_aws_imds() {
+        let endpoint = serve(|request| {
+            match (
+                request.method.as_str(),
+                request.path.as_str(),
+                request.header("X-aws-ec2-metadata-token"),
+            ) {
+                ("PUT", "/latest/api/token", None)
+                    if request
+                        .header("X-aws-ec2-metadata-token-ttl-seconds")
+                        .is_some() =>
+                {
+                    (200, "AQAEAFake-Token==".to_owned())
+                }
+                (_, _, Some(token)) if token != "AQAEAFake-Token==" => (401, String::new()),
+                ("GET", "/latest/meta-data/instance-id", Some(_)) => {
+                    (200, "i-0123456789abcdef0".to_owned())
+                }
+                ("GET", "/latest/meta-data/instance-type", Some(_)) => {
+                    (200, "i4i.xlarge".to_owned())
+                }
+                ("GET", "/latest/meta-data/placement/availability-zone", Some(_)) => {
+                    (200, "us-east-1a".to_owned())
+                }
+                // IMDSv1 requests without a token are rejected.
+                _ => (401, String::new()),
+            }
+        });
+        let actual =
+            block_on(AwsImds::new(Some(&endpoint), Duration::from_secs(5)).instance_metadata());
+        assert_eq!(
+            actual.unwrap(),
+            InstanceMetadata {
+                instance_id: "i-0123456789abcdef0".to_owned(),
+                instance_type: "i4i.xlarge".to_owned(),
+                zone: "us-east-1a".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_gcp_metadata() {
+        let endpoint = serve(|request| {
+            if request.header("Metadata-Flavor") != Some("Google") {
+                return (403, String::new());
+            }
+            match request.path.as_str() {
+                "/computeMetadata/v1/instance/id" => (200, "4520031799277581759".to_owned()),
+                "/computeMetadata/v1/instance/machine-type" => (
+                    200,
+                    "projects/123456789012/machineTypes/n2-standard-8".to_owned(),
+                ),
+                "/computeMetadata/v1/instance/zone" => {
+                    (200, "projects/123456789012/zones/us-central1-a".to_owned())
+                }
+                _ => (404, String::new()),
+            }
+        });
+        let actual =
+            block_on(GcpMetadata::new(Some(&endpoint), Duration::from_secs(5)).instance_metadata());
+        assert_eq!(
+            actual.unwrap(),
+            InstanceMetadata {
+                instance_id: "4520031799277581759".to_owned(),
+                instance_type: "n2-standard-8".to_owned(),
+                zone: "us-central1-a".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_azure_imds() {
+        let compute = TestEnv::new().read_testdata("testdata/metadata/azure/compute.json");
+        let endpoint =
+            serve(
+                move |request| match (request.path.as_str(), request.header("Metadata")) {
+                    ("/metadata/instance/compute?api-version=2021-02-01", Some("true")) => {
+                        (200, compute.clone())
+                    }
+                    _ => (400, String::new()),
+                },
+            );
+        let actual =
+            block_on(AzureImds::new(Some(&endpoint), Duration::from_secs(5)).instance_metadata());
+        assert_eq!(
+            actual.unwrap(),
+            InstanceMetadata {
+                instance_id: "5c08b38e-4d57-4c23-ac45-aca61037f084".to_owned(),
+                instance_type: "Standard_L8s_v3".to_owned(),
+                zone: "eastus2-1".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_metadata_unreachable() {
+        // Accepts connections, but never responds.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let actual =
+            block_on(AwsImds::new(Some(&endpoint), Duration::from_millis(100)).instance_metadata());
+        assert!(matches!(actual, Err(MetadataError::Timeout { .. })));
+        let actual = block_on(fetch_instance_metadata(
+            CloudProvider::Aws,
+            Some(&endpoint),
+            Duration::from_millis(100),
+        ));
+        assert_eq!(actual, None);
+        drop(listener);
+
+        // Nothing listening at all.
+        let actual = block_on(fetch_instance_metadata(
+            CloudProvider::Gcp,
+            Some(&endpoint),
+            Duration::from_millis(100),
+        ));
+        assert_eq!(actual, None);
+
+        let endpoint = serve(|_| (500, String::new()));
+        let actual =
+            block_on(AzureImds::new(Some(&endpoint), Duration::from_secs(5)).instance_metadata());
+        assert!(matches!(actual, Err(MetadataError::Status { .. })));
+
+        // Providers without a backend don't try.
+        let actual = block_on(fetch_instance_metadata(
+            CloudProvider::Generic,
+            Some(&endpoint),
+            Duration::from_secs(5),
+        ));
+        assert_eq!(actual, None);
+    }
+}
@@ -0,0 +1,368 @@
+//! NVMe admin commands, issued directly to the controller with ioctls.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+// _IOWR('N', 0x41, struct nvme_admin_cmd) from linux/nvme_ioctl.h.
+const NVME_IOCTL_ADMIN_CMD: u32 = 0xC048_4E41;
+const NVME_ADMIN_IDENTIFY: u8 = 0x06;
+const NVME_IDENTIFY_CNS_CONTROLLER: u32 = 0x01;
+const IDENTIFY_DATA_LEN: usize = 4096;
+const NVME_ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const NVME_LOG_SMART: u32 = 0x02;
+const SMART_LOG_LEN: usize = 512;
+// Asks for the log of the whole controller, rather than one namespace.
+const NVME_NSID_ALL: u32 = 0xFFFF_FFFF;
+
+// Critical warning bits of the SMART / Health log, and what they mean.
+const CRITICAL_WARNINGS: &[(u8, &str)] = &[
+    (1 << 0, "available spare is below its threshold"),
+    (1 << 1, "temperature is outside its thresholds"),
+    (
+        1 << 2,
+        "reliability is degraded by media or internal errors",
+    ),
+    (1 << 3, "media is read only"),
+    (1 << 4, "volatile memory backup has failed"),
+    (1 << 5, "persistent memory region is read only"),
+];
+
+const AMAZON_VENDOR_ID: u16 = 0x1d0f;
+
+/// struct nvme_passthru_cmd from linux/nvme_ioctl.h.
+#[repr(C)]
+#[derive(Default)]
+struct NvmeAdminCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+/// Issues an admin command that reads `buf.len()` bytes from the controller.
+pub(crate) fn admin_read(
+    controller: &Path,
+    opcode: u8,
+    nsid: u32,
+    cdw10: u32,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let file = File::open(controller)?;
+    let mut cmd = NvmeAdminCmd {
+        opcode,
+        nsid,
+        addr: buf.as_mut_ptr() as u64,
+        data_len: buf.len() as u32,
+        cdw10,
+        ..Default::default()
+    };
+    // SAFETY: `cmd` matches the kernel's struct layout,
+    // and `addr` points at `data_len` writable bytes that outlive the call.
+    let rc = unsafe { libc::ioctl(file.as_raw_fd(), NVME_IOCTL_ADMIN_CMD as _, &mut cmd) };
+    match rc {
+        0 => Ok(()),
+        rc if rc < 0 => Err(io::Error::last_os_error()),
+        status => Err(io::Error::other(format!(
+            "NVMe admin command {opcode:#x} failed with status {status:#x}"
+        ))),
+    }
+}
+
+/// Issues admin commands to NVMe controllers, like `/dev/nvme1`.
+/// Tests stand in for the controllers with captured data.
+pub(crate) trait NvmeAdmin: Send + Sync {
+    fn identify_controller(&self, controller: &Path) -> io::Result<IdentifyController>;
+    fn smart_log(&self, controller: &Path) -> io::Result<SmartLog>;
+}
+
+/// Issues admin commands with ioctls.
+pub(crate) struct IoctlNvmeAdmin;
+
+impl NvmeAdmin for IoctlNvmeAdmin {
+    fn identify_controller(&self, controller: &Path) -> io::Result<IdentifyController> {
+        IdentifyController::read(controller)
+    }
+
+    fn smart_log(&self, controller: &Path) -> io::Result<SmartLog> {
+        SmartLog::read(controller)
+    }
+}
+
+/// The parts of the Identify Controller data structure we care about.
+#[derive(Debug, PartialEq)]
+pub(crate) struct IdentifyController {
+    pub(crate) vendor_id: u16,
+    pub(crate) subsystem_vendor_id: u16,
+    pub(crate) serial: String,
+    pub(crate) model: String,
+    pub(crate) firmware: String,
+    /// The vendor specific area at the end of the structure.
+    pub(crate) vendor_specific: Vec<u8>,
+}
+
+impl IdentifyController {
+    pub(crate) fn read(controller: &Path) -> io::Result<IdentifyController> {
+        let mut data = vec![0u8; IDENTIFY_DATA_LEN];
+        admin_read(
+            controller,
+            NVME_ADMIN_IDENTIFY,
+            0,
+            NVME_IDENTIFY_CNS_CONTROLLER,
+            &mut data,
+        )?;
+        IdentifyController::parse(&data)
+    }
+
+    pub(crate) fn parse(data: &[u8]) -> io::Result<IdentifyController> {
+        if data.len() < IDENTIFY_DATA_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "identify controller data is {} bytes, expected {IDENTIFY_DATA_LEN}",
+                    data.len()
+                ),
+            ));
+        }
+        let string = |range: std::ops::Range<usize>| {
+            String::from_utf8_lossy(&data[range])
+                .trim_end_matches([' ', '\0'])
+                .to_owned()
+        };
+        Ok(IdentifyController {
+            vendor_id: u16::from_le_bytes([data[0], data[1]]),
+            subsystem_vendor_id: u16::from_le_bytes([data[2], data[3]]),
+            serial: string(4..24),
+            model: string(24..64),
+            firmware: string(64..72),
+            vendor_specific: data[3072..IDENTIFY_DATA_LEN].to_vec(),
+        })
+    }
+
+    /// Whether this is an AWS instance store volume, rather than EBS.
+    ///
+    /// Amazon stores the block device mapping name in the first 32 bytes
+    /// of the vendor specific area: `ephemeralN` for instance store,
+    /// and the attachment name like `/dev/xvda` or `sdf` for EBS.
+    /// The model is only used when that is empty.
+    pub(crate) fn is_aws_instance_store(&self) -> bool {
+        if self.vendor_id != AMAZON_VENDOR_ID {
+            return false;
+        }
+        let block_device_mapping = String::from_utf8_lossy(&self.vendor_specific[..32]);
+        let block_device_mapping = block_device_mapping.trim_end_matches([' ', '\0']);
+        if block_device_mapping.is_empty() {
+            self.model.contains("Instance Storage")
+        } else {
+            block_device_mapping.starts_with("ephemeral")
+        }
+    }
+}
+
+/// The parts of the SMART / Health Information log page we care about.
+#[derive(Debug, PartialEq)]
+pub(crate) struct SmartLog {
+    pub(crate) critical_warning: u8,
+    /// Composite temperature in Kelvin.
+    pub(crate) temperature: u16,
+    /// Remaining spare capacity, as a percentage.
+    pub(crate) available_spare: u8,
+    pub(crate) available_spare_threshold: u8,
+    /// Estimate of how much of the rated endurance has been used.
+    /// Goes above 100 once the rated endurance is exceeded.
+    pub(crate) percentage_used: u8,
+    /// Unrecovered data integrity errors over the controller's life.
+    pub(crate) media_errors: u128,
+}
+
+impl SmartLog {
+    pub(crate) fn read(controller: &Path) -> io::Result<SmartLog> {
+        let mut data = vec![0u8; SMART_LOG_LEN];
+        // The number of dwords to read, minus one, goes in the upper half.
+        let dwords = (SMART_LOG_LEN / 4 - 1) as u32;
+        admin_read(
+            controller,
+            NVME_ADMIN_GET_LOG_PAGE,
+            NVME_NSID_ALL,
+            (dwords << 16) | NVME_LOG_SMART,
+            &mut data,
+        )?;
+        SmartLog::parse(&data)
+    }
+
+    pub(crate) fn parse(data: &[u8]) -> io::Result<SmartLog> {
+        if data.len() < SMART_LOG_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "SMART log is {} bytes, expected {SMART_LOG_LEN}",
+                    data.len()
+                ),
+            ));
+        }
+        Ok(SmartLog {
+            critical_warning: data[0],
+            temperature: u16::from_le_bytes([data[1], data[2]]),
+            available_spare: data[3],
+            available_spare_threshold: data[4],
+            percentage_used: data[5],
+            media_errors: u128::from_le_bytes(data[160..176].try_into().unwrap()),
+        })
+    }
+
+    /// Why the disk shouldn't be trusted with data, if it shouldn't.
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = CRITICAL_WARNINGS
+            .iter()
+            .filter(|(bit, _)| self.critical_warning & bit != 0)
+            .map(|(_, warning)| format!("critical warning: {warning}"))
+            .collect();
+        if self.available_spare < self.available_spare_threshold {
+            problems.push(format!(
+                "available spare {}% is below the threshold of {}%",
+                self.available_spare, self.available_spare_threshold
+            ));
+        }
+        if self.percentage_used >= 100 {
+            problems.push(format!(
+                "{}% of its rated endurance is used",
+                self.percentage_used
+            ));
+        }
+        if self.media_errors > 0 {
+            problems.push(format!("{} media errors", self.media_errors));
+        }
+        problems
+    }
+}
+
+/// Splits a namespace block device into its controller character device
+/// and namespace ID, ie: `/dev/nvme1` and 2 for `/dev/nvme1n2`.
+///
+/// This goes by the kernel's naming, which uses the subsystem's number
+/// rather than the controller's with native multipath. The two are the
+/// same unless a subsystem has more than one controller.
+pub(crate) fn split_namespace(namespace_path: &str) -> Option<(&str, u32)> {
+    let (controller, namespace) = namespace_path.rsplit_once('n')?;
+    if controller.ends_with(|c: char| c.is_ascii_digit())
+        && !namespace.is_empty()
+        && namespace.chars().all(|c| c.is_ascii_digit())
+    {
+        Some((controller, namespace.parse().ok()?))
+    } else {
+        None
+    }
+}
+
+/// Returns the controller character device for a namespace block device,
+/// ie: `/dev/nvme1` for `/dev/nvme1n1`.
+pub(crate) fn controller_path(namespace_path: &str) -> Option<&str> {
+    split_namespace(namespace_path).map(|(controller, _)| controller)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::nvme::{IdentifyController, SmartLog, controller_path, split_namespace};
+
+    fn read_identify(path: &str) -> IdentifyController {
+        let data = std::fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap();
+        IdentifyController::parse(&data).unwrap()
+    }
+
+    fn read_smart_log(path: &str) -> SmartLog {
+        let data = std::fs::read(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap();
+        SmartLog::parse(&data).unwrap()
+    }
+
+    #[test]
+    fn test_parse_identify_controller() {
+        let instance_store = read_identify("testdata/nvme/aws/nvme1.bin");
+        assert_eq!(instance_store.vendor_id, 0x1d0f);
+        assert_eq!(instance_store.serial, "AWS3CEF3078A8D77867C");
+        assert_eq!(instance_store.model, "Amazon EC2 NVMe Instance Storage");
+        assert_eq!(instance_store.firmware, "0");
+        assert!(instance_store.is_aws_instance_store());
+
+        let ebs = read_identify("testdata/nvme/aws/nvme0.bin");
+        assert_eq!(ebs.serial, "vol0cd214adef638ef6e");
+        assert_eq!(ebs.model, "Amazon Elastic Block Store");
+        assert_eq!(ebs.firmware, "2.0");
+        assert!(!ebs.is_aws_instance_store());
+
+        let samsung = read_identify("testdata/nvme/samsung.bin");
+        assert_eq!(samsung.vendor_id, 0x144d);
+        assert_eq!(samsung.firmware, "EPK9CB5Q");
+        assert!(!samsung.is_aws_instance_store());
+
+        assert!(IdentifyController::parse(&[0; 512]).is_err());
+    }
+
+    #[test]
+    fn test_parse_smart_log() {
+        let healthy = read_smart_log("testdata/nvme/smart/nvme10.bin");
+        assert_eq!(
+            healthy,
+            SmartLog {
+                critical_warning: 0,
+                temperature: 308,
+                available_spare: 100,
+                available_spare_threshold: 10,
+                percentage_used: 1,
+                media_errors: 0,
+            }
+        );
+        assert!(healthy.problems().is_empty());
+
+        let overheating = read_smart_log("testdata/nvme/smart/nvme11.bin");
+        assert_eq!(overheating.temperature, 358);
+        assert_eq!(
+            overheating.problems(),
+            vec!["critical warning: temperature is outside its thresholds"]
+        );
+
+        let worn_out = read_smart_log("testdata/nvme/smart/nvme12.bin");
+        assert_eq!(
+            worn_out.problems(),
+            vec![
+                "critical warning: available spare is below its threshold",
+                "critical warning: media is read only",
+                "available spare 4% is below the threshold of 10%",
+                "104% of its rated endurance is used",
+            ]
+        );
+
+        let media_errors = read_smart_log("testdata/nvme/smart/nvme13.bin");
+        assert_eq!(media_errors.media_errors, 27);
+        assert_eq!(media_errors.problems(), vec!["27 media errors"]);
+
+        assert!(SmartLog::parse(&[0; 64]).is_err());
+    }
+
+    #[test]
+    fn test_controller_path() {
+        assert_eq!(controller_path("/dev/nvme1n1"), Some("/dev/nvme1"));
+        assert_eq!(controller_path("/dev/nvme12n3"), Some("/dev/nvme12"));
+        assert_eq!(controller_path("/dev/sda"), None);
+        assert_eq!(controller_path("/dev/nvme1n1p2"), None);
+        assert_eq!(split_namespace("/dev/nvme1n2"), Some(("/dev/nvme1", 2)));
+        assert_eq!(split_namespace("/dev/nvme0n10"), Some(("/dev/nvme0", 10)));
+        assert_eq!(split_namespace("/dev/nvme0n"), None);
+    }
+}
@@ -3,21 +3,22 @@ use kube::api::PostParams;
 use kube::{Api, Client};
 use tracing::{info, warn};
 
+use crate::error::Error;
 use crate::load_kube_config;
 
-pub(crate) async fn remove_taint(node_name: &str, taint_key: &str) {
-    let kube_config = load_kube_config().await;
-    let client = Client::try_from(kube_config.clone()).unwrap();
+pub(crate) async fn remove_taint(node_name: &str, taint_key: &str) -> Result<(), Error> {
+    let kube_config = load_kube_config().await?;
+    let client = Client::try_from(kube_config).map_err(Error::kube("failed to create client"))?;
     let node_api: Api<Node> = Api::all(client);
 
     for _ in 0..5 {
         let mut node = node_api
             .get(node_name)
             .await
-            .unwrap_or_else(|e| panic!("Failed to get node {node_name}: {e:?}"));
+            .map_err(Error::kube(format!("failed to get node {node_name}")))?;
         let Some(taint_position) = taint_position(taint_key, &node) else {
             info!("Node {node_name} is not tainted");
-            return;
+            return Ok(());
         };
         info!("Removing taint {taint_key} from node {node_name}");
         node.spec
@@ -33,14 +34,22 @@ pub(crate) async fn remove_taint(node_name: &str, taint_key: &str) {
         {
             Ok(_) => {
                 info!("Taint {taint_key} removed");
-                return;
+                return Ok(());
             }
             Err(kube::Error::Api(e)) if e.code == 409 => {
                 warn!("Conflict while replacing node");
             }
-            Err(e) => panic!("{}", e),
+            Err(e) => {
+                return Err(Error::kube(format!("failed to replace node {node_name}"))(
+                    e,
+                ));
+            }
         };
     }
+    Err(Error::KubeError {
+        context: format!("failed to remove taint {taint_key} from node {node_name}"),
+        source: "too many conflicts while replacing node".into(),
+    })
 }
 
 fn taint_position(taint_key: &str, node: &Node) -> Option<usize> {
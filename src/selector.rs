@@ -0,0 +1,543 @@
+//! A small expression language for selecting block devices by their attributes.
+//!
+//! For example: `model=~"SAMSUNG.*" && size>=1TiB && tran in (nvme,sas)`
+//!
+//! Supported fields are `model`, `serial`, `wwn`, `vendor` and `tran` (strings),
+//! `size` (bytes, with optional units like `375G`, `1TiB` or `500GB`),
+//! and `rota` (`true`/`false` or `1`/`0`).
+//!
+//! String fields support `==`, `!=`, `in (a, b)`, and the regex operators
+//! `=~` and `!~`, which must match the whole (trimmed) value.
+//! `size` supports `==`, `!=`, `<`, `<=`, `>`, `>=` and `in`.
+//! `rota` supports `==` and `!=`.
+//! Values containing anything other than letters, digits and `.-_/:` must be quoted.
+//! Expressions can be combined with `&&`, `||`, `!` and parentheses.
+//! A field the device doesn't report never matches, except with `!=` and `!~`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use regex::Regex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Model,
+    Serial,
+    Wwn,
+    Size,
+    Rota,
+    Tran,
+    Vendor,
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Field, SelectorError> {
+        Ok(match name {
+            "model" => Field::Model,
+            "serial" => Field::Serial,
+            "wwn" => Field::Wwn,
+            "size" => Field::Size,
+            "rota" => Field::Rota,
+            "tran" => Field::Tran,
+            "vendor" => Field::Vendor,
+            _ => return Err(SelectorError(format!("unknown field '{name}'"))),
+        })
+    }
+}
+
+/// The value of a field on a device.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue<'a> {
+    Str(&'a str),
+    Size(u64),
+    Bool(bool),
+    Missing,
+}
+
+/// Something a selector can be evaluated against.
+pub trait Selectable {
+    fn field(&self, field: Field) -> FieldValue<'_>;
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SelectorError(String);
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid selector: {}", self.0)
+    }
+}
+
+impl std::error::Error for SelectorError {}
+
+/// A parsed selector expression.
+#[derive(Clone, Debug)]
+pub struct Selector {
+    source: String,
+    expr: Expr,
+}
+
+impl Selector {
+    pub fn matches(&self, device: &impl Selectable) -> bool {
+        self.expr.eval(device)
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl FromStr for Selector {
+    type Err = SelectorError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(SelectorError(format!("unexpected {token}")));
+        }
+        Ok(Selector {
+            source: source.to_owned(),
+            expr,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, Comparison),
+}
+
+#[derive(Clone, Debug)]
+enum Comparison {
+    StrEq(String),
+    StrIn(Vec<String>),
+    Matches(Regex),
+    Size(Op, u64),
+    SizeIn(Vec<u64>),
+    Bool(bool),
+    Negated(Box<Comparison>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    NotMatch,
+}
+
+impl Expr {
+    fn eval(&self, device: &impl Selectable) -> bool {
+        match self {
+            Expr::And(lhs, rhs) => lhs.eval(device) && rhs.eval(device),
+            Expr::Or(lhs, rhs) => lhs.eval(device) || rhs.eval(device),
+            Expr::Not(expr) => !expr.eval(device),
+            Expr::Compare(field, comparison) => comparison.eval(&device.field(*field)),
+        }
+    }
+}
+
+impl Comparison {
+    fn eval(&self, value: &FieldValue) -> bool {
+        match (self, value) {
+            (Comparison::Negated(comparison), value) => !comparison.eval(value),
+            (Comparison::StrEq(expected), FieldValue::Str(actual)) => actual.trim() == expected,
+            (Comparison::StrIn(expected), FieldValue::Str(actual)) => {
+                expected.iter().any(|expected| actual.trim() == expected)
+            }
+            (Comparison::Matches(regex), FieldValue::Str(actual)) => regex.is_match(actual.trim()),
+            (Comparison::Size(op, expected), FieldValue::Size(actual)) => match op {
+                Op::Eq => actual == expected,
+                Op::Lt => actual < expected,
+                Op::Le => actual <= expected,
+                Op::Gt => actual > expected,
+                Op::Ge => actual >= expected,
+                Op::Ne | Op::Match | Op::NotMatch => unreachable!("rejected by the parser"),
+            },
+            (Comparison::SizeIn(expected), FieldValue::Size(actual)) => expected.contains(actual),
+            (Comparison::Bool(expected), FieldValue::Bool(actual)) => actual == expected,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Op(Op),
+    In,
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    Comma,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::Quoted(quoted) => write!(f, "\"{quoted}\""),
+            Token::Op(op) => write!(f, "operator {op:?}"),
+            Token::In => f.write_str("'in'"),
+            Token::And => f.write_str("'&&'"),
+            Token::Or => f.write_str("'||'"),
+            Token::Not => f.write_str("'!'"),
+            Token::LParen => f.write_str("'('"),
+            Token::RParen => f.write_str("')'"),
+            Token::Comma => f.write_str("','"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, SelectorError> {
+    let mut tokens = vec![];
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            ',' => Token::Comma,
+            '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+            '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Eq),
+            '=' if chars.next_if_eq(&'~').is_some() => Token::Op(Op::Match),
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Ne),
+            '!' if chars.next_if_eq(&'~').is_some() => Token::Op(Op::NotMatch),
+            '!' => Token::Not,
+            '<' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '"' => {
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => quoted.push(escaped),
+                            None => return Err(SelectorError("unterminated string".to_owned())),
+                        },
+                        Some(c) => quoted.push(c),
+                        None => return Err(SelectorError("unterminated string".to_owned())),
+                    }
+                }
+                Token::Quoted(quoted)
+            }
+            c if is_word_char(c) => {
+                let mut word = String::from(c);
+                while let Some(c) = chars.next_if(|c| is_word_char(*c)) {
+                    word.push(c);
+                }
+                if word == "in" {
+                    Token::In
+                } else {
+                    Token::Word(word)
+                }
+            }
+            c => return Err(SelectorError(format!("unexpected character '{c}'"))),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '/' | ':')
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, SelectorError> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| SelectorError("unexpected end of expression".to_owned()))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), SelectorError> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(SelectorError(format!("expected {expected}, found {token}"))),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, SelectorError> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, SelectorError> {
+        let mut expr = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, SelectorError> {
+        match self.next()? {
+            Token::Not => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Token::LParen => {
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Token::Word(name) => {
+                let field = Field::parse(&name)?;
+                let comparison = self.parse_comparison(field)?;
+                Ok(Expr::Compare(field, comparison))
+            }
+            token => Err(SelectorError(format!("expected a field, found {token}"))),
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<String, SelectorError> {
+        match self.next()? {
+            Token::Word(value) | Token::Quoted(value) => Ok(value),
+            token => Err(SelectorError(format!("expected a value, found {token}"))),
+        }
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<String>, SelectorError> {
+        self.expect(Token::LParen)?;
+        let mut values = vec![self.parse_value()?];
+        loop {
+            match self.next()? {
+                Token::Comma => values.push(self.parse_value()?),
+                Token::RParen => return Ok(values),
+                token => return Err(SelectorError(format!("expected ',' or ')', found {token}"))),
+            }
+        }
+    }
+
+    fn parse_comparison(&mut self, field: Field) -> Result<Comparison, SelectorError> {
+        let op = match self.next()? {
+            Token::In => {
+                let values = self.parse_list()?;
+                return match field {
+                    Field::Size => Ok(Comparison::SizeIn(
+                        values
+                            .iter()
+                            .map(|value| parse_size(value))
+                            .collect::<Result<_, _>>()?,
+                    )),
+                    Field::Rota => Err(SelectorError("'in' is not supported for rota".to_owned())),
+                    _ => Ok(Comparison::StrIn(values)),
+                };
+            }
+            Token::Op(op) => op,
+            token => {
+                return Err(SelectorError(format!(
+                    "expected an operator after {field:?}, found {token}"
+                )));
+            }
+        };
+        let value = self.parse_value()?;
+        let comparison = match (field, op) {
+            (Field::Size, Op::Ne) => {
+                Comparison::Negated(Box::new(Comparison::Size(Op::Eq, parse_size(&value)?)))
+            }
+            (Field::Size, Op::Match | Op::NotMatch) => {
+                return Err(SelectorError(format!("{op:?} is not supported for size")));
+            }
+            (Field::Size, op) => Comparison::Size(op, parse_size(&value)?),
+            (Field::Rota, Op::Eq | Op::Ne) => {
+                let expected = match value.as_str() {
+                    "true" | "1" => true,
+                    "false" | "0" => false,
+                    _ => return Err(SelectorError(format!("invalid boolean '{value}'"))),
+                };
+                let comparison = Comparison::Bool(expected);
+                if op == Op::Ne {
+                    Comparison::Negated(Box::new(comparison))
+                } else {
+                    comparison
+                }
+            }
+            (Field::Rota, op) => {
+                return Err(SelectorError(format!("{op:?} is not supported for rota")));
+            }
+            (_, Op::Eq) => Comparison::StrEq(value),
+            (_, Op::Ne) => Comparison::Negated(Box::new(Comparison::StrEq(value))),
+            (_, Op::Match | Op::NotMatch) => {
+                let regex = Regex::new(&format!("^(?:{value})$"))
+                    .map_err(|e| SelectorError(format!("invalid regex '{value}': {e}")))?;
+                if op == Op::NotMatch {
+                    Comparison::Negated(Box::new(Comparison::Matches(regex)))
+                } else {
+                    Comparison::Matches(regex)
+                }
+            }
+            (field, op) => {
+                return Err(SelectorError(format!(
+                    "{op:?} is not supported for {field:?}"
+                )));
+            }
+        };
+        Ok(comparison)
+    }
+}
+
+/// Parses a size in bytes.
+///
+/// Accepts plain byte counts, binary units (`K`, `KiB`, `M`, `MiB`, ...),
+/// which is also how lsblk reports sizes, and decimal units (`KB`, `MB`, ...).
+/// Fractional values like `441.4G` are allowed.
+pub fn parse_size(value: &str) -> Result<u64, SelectorError> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| SelectorError(format!("invalid size '{value}'")))?;
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        "P" | "PiB" => 1 << 50,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        "PB" => 1_000_000_000_000_000,
+        _ => return Err(SelectorError(format!("invalid size unit in '{value}'"))),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::selector::{Field, FieldValue, Selectable, Selector, parse_size};
+
+    #[derive(Default)]
+    struct Device {
+        model: Option<&'static str>,
+        size: Option<u64>,
+        rota: Option<bool>,
+        tran: Option<&'static str>,
+    }
+
+    impl Selectable for Device {
+        fn field(&self, field: Field) -> FieldValue<'_> {
+            let value = match field {
+                Field::Model => self.model.map(FieldValue::Str),
+                Field::Size => self.size.map(FieldValue::Size),
+                Field::Rota => self.rota.map(FieldValue::Bool),
+                Field::Tran => self.tran.map(FieldValue::Str),
+                _ => None,
+            };
+            value.unwrap_or(FieldValue::Missing)
+        }
+    }
+
+    fn matches(selector: &str, device: &Device) -> bool {
+        selector.parse::<Selector>().unwrap().matches(device)
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("1TiB"), Ok(1 << 40));
+        assert_eq!(parse_size("1T"), Ok(1 << 40));
+        assert_eq!(parse_size("375G"), Ok(375 << 30));
+        assert_eq!(parse_size("500GB"), Ok(500_000_000_000));
+        assert_eq!(parse_size("1.5K"), Ok(1536));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("1XB").is_err());
+    }
+
+    #[test]
+    fn test_selector() {
+        let samsung = Device {
+            model: Some("SAMSUNG MZWLJ7T6HALA-00007   "),
+            size: Some(7 << 40),
+            rota: Some(false),
+            tran: Some("nvme"),
+        };
+        let spinning = Device {
+            model: Some("ST4000NM0035"),
+            size: Some(4 << 40),
+            rota: Some(true),
+            tran: Some("sas"),
+        };
+
+        let selector = r#"model=~"SAMSUNG.*" && size>=1TiB && tran in (nvme,sas)"#;
+        assert!(matches(selector, &samsung));
+        assert!(!matches(selector, &spinning));
+
+        assert!(matches("rota == true || size < 1T", &spinning));
+        assert!(!matches("!(rota == 1)", &spinning));
+        assert!(matches("tran != nvme", &spinning));
+        assert!(matches(r#"model == "ST4000NM0035""#, &spinning));
+        assert!(matches(r#"model !~ "SAMSUNG.*""#, &spinning));
+        // Regexes must match the whole value.
+        assert!(!matches("model =~ SAMSUNG", &samsung));
+        assert!(matches("size in (4TiB, 7TiB)", &spinning));
+
+        // Missing fields only match negations.
+        let unknown = Device::default();
+        assert!(!matches("tran == nvme", &unknown));
+        assert!(matches("tran != nvme", &unknown));
+        assert!(!matches("size > 0", &unknown));
+    }
+
+    #[test]
+    fn test_selector_errors() {
+        for selector in [
+            "",
+            "colour == red",
+            "model",
+            "model ==",
+            "size =~ 1T",
+            "size > big",
+            "rota > 1",
+            "rota == maybe",
+            "model == a &&",
+            "(model == a",
+            "model == \"a",
+            "model =~ \"(\"",
+            "tran in nvme",
+            "model == a model == b",
+        ] {
+            assert!(
+                selector.parse::<Selector>().is_err(),
+                "expected '{selector}' to be rejected"
+            );
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::cmp::Reverse;
 use std::collections::BTreeMap;
 use std::fs;
 use std::io::ErrorKind;
@@ -5,7 +6,11 @@ use std::io::ErrorKind;
 use serde_yaml::{Mapping, Value};
 use tracing::info;
 
-use crate::detect::DiskDetectorTrait;
+use crate::detect::{DiskDetectorTrait, DiskInfo};
+use crate::disk_ids::DiskIds;
+use crate::error::Error;
+use crate::host_root::HostRoot;
+use crate::in_use::check_not_in_use;
 use crate::remove_taint::remove_taint;
 use crate::{CloudProvider, Commander};
 
@@ -13,6 +18,7 @@ pub struct SwapController<D: DiskDetectorTrait> {
     pub cloud_provider: CloudProvider,
     pub commander: Commander,
     pub disk_detector: D,
+    pub host_root: HostRoot,
     pub node_name: Option<String>,
     pub taint_key: String,
     pub bottlerocket_enable_swap: bool,
@@ -22,24 +28,58 @@ pub struct SwapController<D: DiskDetectorTrait> {
     pub vm_swappiness: usize,
     pub vm_min_free_kbytes: usize,
     pub vm_watermark_scale_factor: usize,
+    pub swap_group_priorities: bool,
 }
+// Priority of the swap devices, or of the least preferred group
+// with group priorities.
+const SWAP_PRIORITY: usize = 10;
+
 impl<D: DiskDetectorTrait> SwapController<D> {
-    pub async fn setup(&self) {
+    pub async fn setup(&self) -> Result<(), Error> {
         info!("Starting NVMe disk configuration with swap...");
-        let devices = self.disk_detector.detect_devices();
-        for device in &devices {
-            if !self.is_existing_swap(device) {
-                info!("Configuring swap on {device}");
-                self.mkswap(device);
-                self.swapon(device);
+        let mut groups = self.disk_detector.detect_device_groups()?;
+        if self.swap_group_priorities {
+            // Linux only spreads pages across devices with the same priority,
+            // and fills higher priorities first.
+            groups.sort_by_key(|group| Reverse(group.preference()));
+        }
+        // Devices we already swap to are in use by us.
+        let disk_ids = DiskIds::read(&self.host_root);
+        let mut new_disks = vec![];
+        for disk in groups.iter().flat_map(|group| &group.devices) {
+            if !self.is_existing_swap(&disk_ids, disk)? {
+                new_disks.push(disk.clone());
+            }
+        }
+        // Disks to reclaim are checked once the host gives them up.
+        let new_devices: Vec<String> = new_disks.iter().map(|disk| disk.path.clone()).collect();
+        let not_reclaimed: Vec<String> = new_disks
+            .iter()
+            .filter(|disk| !disk.reclaim)
+            .map(|disk| disk.path.clone())
+            .collect();
+        check_not_in_use(&self.host_root, &not_reclaimed)?;
+        self.disk_detector.reclaim(&new_disks)?;
+        for (i, group) in groups.iter().enumerate() {
+            let priority = if self.swap_group_priorities {
+                SWAP_PRIORITY + groups.len() - 1 - i
+            } else {
+                SWAP_PRIORITY
+            };
+            for disk in &group.devices {
+                if new_devices.contains(&disk.path) {
+                    info!("Configuring swap on {disk} with priority {priority}");
+                    self.mkswap(disk.stable_path())?;
+                    self.swapon(disk.stable_path(), priority)?;
+                }
             }
         }
 
         if self.apply_sysctls {
             info!("Setting sysctls to improve swap performance and safety");
-            self.sysctl("vm.swappiness", self.vm_swappiness);
-            self.sysctl("vm.min_free_kbytes", self.vm_min_free_kbytes);
-            self.sysctl("vm.watermark_scale_factor", self.vm_watermark_scale_factor);
+            self.sysctl("vm.swappiness", self.vm_swappiness)?;
+            self.sysctl("vm.min_free_kbytes", self.vm_min_free_kbytes)?;
+            self.sysctl("vm.watermark_scale_factor", self.vm_watermark_scale_factor)?;
         }
 
         if self.bottlerocket_enable_swap {
@@ -48,44 +88,56 @@ impl<D: DiskDetectorTrait> SwapController<D> {
                 "apiclient",
                 "set",
                 "settings.kubernetes.memory-swap-behavior=LimitedSwap",
-            ]);
+            ])?;
         }
 
         if self.hack_restart_kubelet_enable_swap {
             info!("Hackily enabling swap by modifying the Kubelet config and restarting it.");
+            // Check the host root works as a chroot argument before changing anything.
+            let root = self.host_root.root().to_str().ok_or_else(|| {
+                Error::Unsupported(format!(
+                    "host root '{}' is not valid UTF-8",
+                    self.host_root.root().display()
+                ))
+            })?;
             match self.cloud_provider {
                 CloudProvider::Gcp => {
-                    self.update_kubelet_config("/host/home/kubernetes/kubelet-config.yaml");
+                    self.update_kubelet_config("/home/kubernetes/kubelet-config.yaml")?;
                 }
                 CloudProvider::Azure => {
                     // Azure doesn't use a kubelet config file by default,
                     // and there isn't a command line flag to enable LimitedSwap.
-                    self.update_kubelet_config("/host/var/lib/kubelet/config.yaml");
+                    self.update_kubelet_config("/var/lib/kubelet/config.yaml")?;
                     // Azure does reference an env var for the kubelet config file args,
                     // but it isn't set initially.
+                    let dropin = self
+                        .host_root
+                        .path("/etc/systemd/system/kubelet.service.d/99-enable-swap.conf");
                     fs::write(
-                        "/host/etc/systemd/system/kubelet.service.d/99-enable-swap.conf",
+                        &dropin,
                         r#"[Service]
 Environment="KUBELET_CONFIG_FILE_FLAGS=--config /var/lib/kubelet/config.yaml""#,
                     )
-                    .unwrap();
+                    .map_err(Error::io(&dropin))?;
+                }
+                _ => {
+                    return Err(Error::Unsupported(format!(
+                        "Hack enabling swap by restarting the kubelet is not supported for cloud provider: {:?}",
+                        self.cloud_provider
+                    )));
                 }
-                _ => panic!(
-                    "Hack enabling swap by restarting the kubelet is not supported for cloud provider: {:?}",
-                    self.cloud_provider
-                ),
             }
 
             self.commander
-                .check_output(&["chroot", "/host", "systemctl", "daemon-reload"]);
+                .check_output(&["chroot", root, "systemctl", "daemon-reload"])?;
 
             self.commander.check_output(&[
                 "chroot",
-                "/host",
+                root,
                 "systemctl",
                 "restart",
                 "kubelet.service",
-            ]);
+            ])?;
         }
 
         info!("Swap setup completed successfully");
@@ -94,47 +146,58 @@ Environment="KUBELET_CONFIG_FILE_FLAGS=--config /var/lib/kubelet/config.yaml""#,
                 self.node_name.as_ref().expect("clap enforced"),
                 &self.taint_key,
             )
-            .await;
+            .await?;
         }
+        Ok(())
     }
 
-    fn mkswap(&self, device: &str) {
-        self.commander.check_output(&["mkswap", device]);
+    fn mkswap(&self, device: &str) -> Result<(), Error> {
+        self.commander.check_output(&["mkswap", device])?;
+        Ok(())
     }
 
-    fn swapon(&self, device: &str) {
-        // Explicitly set all devices to the same priority, so Linux will
-        // allocate pages to disks round-robin, allowing for faster I/O
-        // on machines with multiple disks.
-        self.commander.check_output(&["swapon", "-p", "10", device]);
+    fn swapon(&self, device: &str, priority: usize) -> Result<(), Error> {
+        // Explicitly set all devices in a group to the same priority,
+        // so Linux will allocate pages to disks round-robin,
+        // allowing for faster I/O on machines with multiple disks.
+        self.commander
+            .check_output(&["swapon", "-p", &priority.to_string(), device])?;
+        Ok(())
     }
 
-    fn is_existing_swap(&self, device: &str) -> bool {
+    fn is_existing_swap(&self, disk_ids: &DiskIds, disk: &DiskInfo) -> Result<bool, Error> {
         // /proc/swaps has contents like:
         // Filename				Type		Size		Used		Priority
         // /nvme0n1                                partition	393215996	0		-2
-        std::fs::read_to_string("/proc/swaps")
-            .expect("failed to read /proc/swaps")
+        let path = self.host_root.path("/proc/swaps");
+        Ok(std::fs::read_to_string(&path)
+            .map_err(Error::io(&path))?
             .trim()
             .lines()
             .skip(1)
-            .map(|line| line.split_whitespace().next().unwrap())
+            .filter_map(|line| line.split_whitespace().next())
             // /proc/swaps is inconsistent in how it reports things,
-            // sometimes leaving off the /dev at the beginning of the path.
-            .any(|line| device.ends_with(line))
+            // sometimes leaving off the /dev at the beginning of the path,
+            // so we compare stable links, or failing that, kernel names.
+            .any(|filename| disk_ids.same_disk(filename, &disk.path)))
     }
 
-    fn sysctl(&self, key: &str, value: usize) {
+    fn sysctl(&self, key: &str, value: usize) -> Result<(), Error> {
         self.commander
-            .check_output(&["sysctl", &format!("{key}={value}")]);
+            .check_output(&["sysctl", &format!("{key}={value}")])?;
+        Ok(())
     }
 
-    fn update_kubelet_config(&self, path: &str) {
+    fn update_kubelet_config(&self, host_path: &str) -> Result<(), Error> {
+        let path = self.host_root.path(host_path);
         // Read existing configuration, if any.
-        let mut kubelet_config: BTreeMap<String, Value> = match fs::read(path) {
-            Ok(data) => serde_yaml::from_slice(&data).unwrap(),
+        let mut kubelet_config: BTreeMap<String, Value> = match fs::read(&path) {
+            Ok(data) => serde_yaml::from_slice(&data).map_err(|e| Error::ParseError {
+                what: format!("kubelet config '{}'", path.display()),
+                message: e.to_string(),
+            })?,
             Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
-            Err(e) => panic!("failed to read kubelet config {path}: {e:?}"),
+            Err(e) => return Err(Error::io(&path)(e)),
         };
 
         // Ensure we have the type information, in case we're making a new file.
@@ -155,6 +218,148 @@ Environment="KUBELET_CONFIG_FILE_FLAGS=--config /var/lib/kubelet/config.yaml""#,
         kubelet_config.insert("memorySwap".to_owned(), Value::Mapping(memory_swap));
 
         // Write the updates.
-        fs::write(path, serde_yaml::to_string(&kubelet_config).unwrap()).unwrap();
+        fs::write(&path, serde_yaml::to_string(&kubelet_config).unwrap()).map_err(Error::io(&path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::CloudProvider;
+    use crate::detect::{DiskDetector, HealthPolicy};
+    use crate::host_root::HostRoot;
+    use crate::swap::SwapController;
+    use crate::test::TestEnv;
+
+    /// A host root with the given devices and no swap yet, and a controller
+    /// for it whose mkswap and swapon calls are logged.
+    fn test_controller(
+        test_env: &TestEnv,
+        cloud_provider: CloudProvider,
+        devices: &[&str],
+    ) -> (SwapController<DiskDetector>, PathBuf) {
+        // The host's device nodes are only in its root filesystem.
+        let rootfs = test_env.temp_dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("dev")).unwrap();
+        fs::create_dir_all(rootfs.join("proc")).unwrap();
+        for device in devices {
+            fs::write(rootfs.join("dev").join(device), "").unwrap();
+        }
+        let swaps = rootfs.join("proc/swaps");
+        fs::write(&swaps, "Filename\tType\tSize\tUsed\tPriority\n").unwrap();
+        let log = test_env.temp_dir.path().join("commands.log");
+        test_env.mock_script(
+            "mkswap",
+            &format!("echo \"mkswap $*\" >> {}\n", log.display()),
+        );
+        // Like the kernel, /proc/swaps lists the device by its host path.
+        test_env.mock_script(
+            "swapon",
+            &format!(
+                "echo \"swapon $*\" >> {log}\n\
+                 printf '/dev/%s\\tpartition\\t8388604\\t0\\t%s\\n' \"$(basename \"$3\")\" \"$2\" >> {swaps}\n",
+                log = log.display(),
+                swaps = swaps.display(),
+            ),
+        );
+        let host_root = HostRoot::new(&rootfs);
+        let swap_controller = SwapController {
+            cloud_provider,
+            commander: test_env.commander.clone(),
+            disk_detector: DiskDetector::builder(cloud_provider)
+                .commander(test_env.commander.clone())
+                .host_root(host_root.clone())
+                .health_policy(HealthPolicy::Ignore)
+                .build()
+                .unwrap(),
+            host_root,
+            node_name: None,
+            taint_key: String::new(),
+            bottlerocket_enable_swap: false,
+            hack_restart_kubelet_enable_swap: false,
+            remove_taint: false,
+            apply_sysctls: false,
+            vm_swappiness: 0,
+            vm_min_free_kbytes: 0,
+            vm_watermark_scale_factor: 0,
+            swap_group_priorities: false,
+        };
+        (swap_controller, log)
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_setup_twice() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/aws/lsblk.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let (swap_controller, log) = test_controller(&test_env, CloudProvider::Aws, &["nvme1n1"]);
+
+        // The second time, the disk we swap to is already set up.
+        let runtime = runtime();
+        runtime.block_on(swap_controller.setup()).unwrap();
+        runtime.block_on(swap_controller.setup()).unwrap();
+        let device = swap_controller.host_root.device("/dev/nvme1n1");
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            format!("mkswap {device}\nswapon -p 10 {device}\n")
+        );
+    }
+
+    #[test]
+    fn test_group_priorities() {
+        let test_env = TestEnv::new();
+        let lsblk_output = test_env.read_testdata("testdata/lsblk_mixed.json");
+        test_env.mock("lsblk", 0, &lsblk_output);
+        let devices = ["nvme0n1", "nvme1n1", "nvme2n1", "nvme3n1", "nvme4n1"];
+        let (swap_controller, log) = test_controller(&test_env, CloudProvider::Generic, &devices);
+        let swapped = |priorities: &[(&str, usize)]| {
+            priorities
+                .iter()
+                .map(|(name, priority)| {
+                    let device = swap_controller.host_root.device(&format!("/dev/{name}"));
+                    format!("mkswap {device}\nswapon -p {priority} {device}\n")
+                })
+                .collect::<String>()
+        };
+
+        // By default, all the disks share a priority.
+        runtime().block_on(swap_controller.setup()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            swapped(&[
+                ("nvme0n1", 10),
+                ("nvme3n1", 10),
+                ("nvme1n1", 10),
+                ("nvme2n1", 10),
+                ("nvme4n1", 10),
+            ])
+        );
+
+        // With group priorities, the group with more disks comes first.
+        let (swap_controller, log) = test_controller(&test_env, CloudProvider::Generic, &devices);
+        fs::remove_file(&log).unwrap();
+        let swap_controller = SwapController {
+            swap_group_priorities: true,
+            ..swap_controller
+        };
+        runtime().block_on(swap_controller.setup()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            swapped(&[
+                ("nvme1n1", 11),
+                ("nvme2n1", 11),
+                ("nvme4n1", 11),
+                ("nvme0n1", 10),
+                ("nvme3n1", 10),
+            ])
+        );
     }
 }